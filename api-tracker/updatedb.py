@@ -11,6 +11,26 @@ import sys
 SQL_FILE_PATH_FMT = "update-statistics-{}.sql"
 
 
+STATS_COLUMNS = [
+    "timestamp",
+    "pool_group",
+    "pool_type",
+    "user_address",
+    "action_count_longs",
+    "action_count_shorts",
+    "action_count_lps",
+    "volume_longs",
+    "volume_shorts",
+    "volume_lps",
+    "pnl_longs",
+    "pnl_shorts",
+    "pnl_lps",
+    "tvl_longs",
+    "tvl_shorts",
+    "tvl_lps",
+]
+
+
 def generate_user_insert(addrs):
     unique_addrs = set(addrs)
     return f"""
@@ -25,10 +45,11 @@ WHERE NOT EXISTS (
 def generate_stats_upsert(rows):
     return f"""
 INSERT INTO public.statistics
-    (timestamp, pool_type, user_address, action_count_longs, action_count_shorts, action_count_lps, volume_longs, volume_shorts, volume_lps, pnl_longs, pnl_shorts, pnl_lps, tvl_longs, tvl_shorts, tvl_lps)
+    ({", ".join(STATS_COLUMNS)})
 VALUES
 {",\n".join("('" + "', '".join(row) + "')" for row in rows)}
-ON CONFLICT (timestamp, pool_type, user_address) DO UPDATE SET
+ON CONFLICT (timestamp, pool_group, user_address) DO UPDATE SET
+    pool_type = EXCLUDED.pool_type,
     action_count_longs = EXCLUDED.action_count_longs,
     action_count_shorts = EXCLUDED.action_count_shorts,
     action_count_lps = EXCLUDED.action_count_lps,
@@ -44,11 +65,29 @@ ON CONFLICT (timestamp, pool_type, user_address) DO UPDATE SET
     """
 
 
+# Rows are unique per pool group, `pool_type` is not when grouping by pool address. The
+# `pool_group` column comes with motivator/migrations/0001_statistics_pool_group.sql.
 SQL_STATS_CREATE_INDEX = """
-CREATE UNIQUE INDEX IF NOT EXISTS idx_statistics_on_timestamp_pool_user ON public.statistics (timestamp, pool_type, user_address);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_statistics_on_timestamp_group_user ON public.statistics (timestamp, pool_group, user_address);
 """
 
 
+def is_excluded(row):
+    """Aggregate of the addresses filtered out by --allow/--deny, not a user."""
+    return row.get("excluded") == "true"
+
+
+def check_pool_type(row):
+    """The motivator buckets statistics by pool type, rows grouped by base token or all pools have
+    none."""
+    if not row["pool_type"]:
+        sys.exit(
+            f"Row of pool group {row['pool_group']} has no pool type, "
+            "aggregate with --group_by address or pool_type"
+        )
+    return row
+
+
 def chunks(iterable, size):
     it = iter(iterable)
     while True:
@@ -65,17 +104,22 @@ def main():
     statements = []
 
     with open(csv_file_path, newline="", encoding="utf-8") as csvfile:
-        reader = csv.reader(csvfile)
-        next(reader)  # Skip the header row
-        statements.append(generate_user_insert(r[3] for r in reader))
+        reader = csv.DictReader(csvfile)
+        statements.append(
+            generate_user_insert(r["user_address"] for r in reader if not is_excluded(r))
+        )
 
     statements.append(SQL_STATS_CREATE_INDEX)
 
     with open(csv_file_path, newline="", encoding="utf-8") as csvfile:
-        reader = csv.reader(csvfile)
-        next(reader)  # Skip the header row
-        no_block_num_rows = (r[:1] + r[2:] for r in reader)
-        for chunk in chunks(no_block_num_rows, chunk_size):
+        reader = csv.DictReader(csvfile)
+        # Only the statistics columns, the tracker may emit more.
+        stats_rows = (
+            [check_pool_type(r)[col] for col in STATS_COLUMNS]
+            for r in reader
+            if not is_excluded(r)
+        )
+        for chunk in chunks(stats_rows, chunk_size):
             print("chunk")
             statements.append(generate_stats_upsert(chunk))
 
@@ -1,3 +1,4 @@
+use std::collections::{hash_map::Entry, HashMap};
 use std::fs;
 use std::io::Write;
 use std::sync::Arc;
@@ -5,28 +6,33 @@ use std::sync::Arc;
 use ethers::{
     contract::LogMeta,
     providers::{Middleware, Provider, Ws},
-    types::{I256, U256, U64},
+    types::{H160, H256, I256, U256, U64},
 };
+use rust_decimal::Decimal;
 
 use hyperdrive_wrappers::wrappers::ihyperdrive::i_hyperdrive;
 
+use crate::globals::*;
 use crate::types::*;
 use crate::utils::*;
-use eyre::Result;
+use eyre::{eyre, Result};
 
 async fn record_open_long(
     client: Arc<Provider<Ws>>,
+    tconf: &SingleTrackerConfig,
     events: Arc<Events>,
     event: i_hyperdrive::OpenLongFilter,
     meta: LogMeta,
+    gas: Option<GasCost>,
+    fees: Option<TradeFees>,
 ) -> Result<()> {
     tracing::debug!(
         block_num=%meta.block_number,
         trader=%event.trader,
         maturity_time_str=%timestamp_to_string(event.maturity_time),
         maturity_time=?event.maturity_time,
-        base_amount=%event.base_amount/U256::exp10(18),
-        bond_amount=%event.bond_amount/U256::exp10(18),
+        base_amount=%event.base_amount/U256::exp10(tconf.base_decimals as usize),
+        bond_amount=%event.bond_amount/U256::exp10(tconf.base_decimals as usize),
         "OpenLong",
     );
 
@@ -39,11 +45,20 @@ async fn record_open_long(
         .await?
         .unwrap()
         .timestamp;
+    let base_amount = I256::from_raw(event.base_amount);
+    let bond_amount = I256::from_raw(event.bond_amount);
     let opening = PositionDebit {
         block_number: meta.block_number,
         timestamp: block_timestamp,
-        base_amount: I256::from_raw(event.base_amount),
-        bond_amount: I256::from_raw(event.bond_amount),
+        base_amount,
+        bond_amount,
+        fixed_rate: calc_long_fixed_rate(
+            base_amount.to_wad(tconf.base_decimals),
+            bond_amount.to_wad(tconf.base_decimals),
+            tconf.pool_config.position_duration,
+        ),
+        gas,
+        fees,
     };
     let long: Long = vec![opening];
     events
@@ -57,16 +72,19 @@ async fn record_open_long(
 
 async fn record_close_long(
     client: Arc<Provider<Ws>>,
+    tconf: &SingleTrackerConfig,
     events: Arc<Events>,
     event: i_hyperdrive::CloseLongFilter,
     meta: LogMeta,
+    gas: Option<GasCost>,
+    fees: Option<TradeFees>,
 ) -> Result<()> {
     tracing::debug!(
         block_num=%meta.block_number,
         trader=%event.trader,
         maturity_time=%timestamp_to_string(event.maturity_time),
-        base_amount=%event.base_amount/U256::exp10(18),
-        bond_amount=%event.bond_amount/U256::exp10(18),
+        base_amount=%event.base_amount/U256::exp10(tconf.base_decimals as usize),
+        bond_amount=%event.bond_amount/U256::exp10(tconf.base_decimals as usize),
         "CloseLong"
     );
 
@@ -85,30 +103,33 @@ async fn record_close_long(
         timestamp: block_timestamp,
         base_amount: -I256::from_raw(event.base_amount),
         bond_amount: -I256::from_raw(event.bond_amount),
+        fixed_rate: None,
+        gas,
+        fees,
     };
-    events
-        .longs
-        .entry(key)
-        .and_modify(|existing| existing.push(closing))
-        .or_insert_with(|| {
-            panic!("CloseLong position doesn't exist: {}", key_repr);
-        });
+    match events.longs.get_mut(&key) {
+        Some(mut existing) => existing.push(closing),
+        None => tracing::warn!(key=%key_repr, meta=?meta, "CloseLongWithoutOpen"),
+    }
 
     Ok(())
 }
 
 async fn record_open_short(
     client: Arc<Provider<Ws>>,
+    tconf: &SingleTrackerConfig,
     events: Arc<Events>,
     event: i_hyperdrive::OpenShortFilter,
     meta: LogMeta,
+    gas: Option<GasCost>,
+    fees: Option<TradeFees>,
 ) -> Result<PositionKey> {
     tracing::debug!(
         block_num=%meta.block_number,
         trader=%event.trader,
         maturity_time=%event.maturity_time,
-        base_amount=%event.base_amount/U256::exp10(18),
-        bond_amount=%event.bond_amount/U256::exp10(18),
+        base_amount=%event.base_amount/U256::exp10(tconf.base_decimals as usize),
+        bond_amount=%event.bond_amount/U256::exp10(tconf.base_decimals as usize),
         "OpenShort"
     );
 
@@ -121,11 +142,20 @@ async fn record_open_short(
         .await?
         .unwrap()
         .timestamp;
+    let base_amount = I256::from_raw(event.base_amount);
+    let bond_amount = I256::from_raw(event.bond_amount);
     let opening = PositionDebit {
         block_number: meta.block_number,
         timestamp: block_timestamp,
-        base_amount: I256::from_raw(event.base_amount),
-        bond_amount: I256::from_raw(event.bond_amount),
+        base_amount,
+        bond_amount,
+        fixed_rate: calc_short_fixed_rate(
+            base_amount.to_wad(tconf.base_decimals),
+            bond_amount.to_wad(tconf.base_decimals),
+            tconf.pool_config.position_duration,
+        ),
+        gas,
+        fees,
     };
     let short: Short = vec![opening];
     events
@@ -222,16 +252,19 @@ async fn record_share_price(
 
 async fn record_close_short(
     client: Arc<Provider<Ws>>,
+    tconf: &SingleTrackerConfig,
     events: Arc<Events>,
     event: i_hyperdrive::CloseShortFilter,
     meta: LogMeta,
+    gas: Option<GasCost>,
+    fees: Option<TradeFees>,
 ) -> Result<()> {
     tracing::debug!(
         block_num=%meta.block_number,
         trader=%event.trader,
         maturity_time=%event.maturity_time,
-        base_amount=%event.base_amount/U256::exp10(18),
-        bond_amount=%event.bond_amount/U256::exp10(18),
+        base_amount=%event.base_amount/U256::exp10(tconf.base_decimals as usize),
+        bond_amount=%event.bond_amount/U256::exp10(tconf.base_decimals as usize),
         "CloseShort"
     );
 
@@ -250,29 +283,31 @@ async fn record_close_short(
         timestamp: block_timestamp,
         base_amount: -I256::from_raw(event.base_amount),
         bond_amount: -I256::from_raw(event.bond_amount),
+        fixed_rate: None,
+        gas,
+        fees,
     };
-    events
-        .shorts
-        .entry(key)
-        .and_modify(|existing| existing.push(closing))
-        .or_insert_with(|| {
-            panic!("CloseShort position doesn't exist: {}", key_repr);
-        });
+    match events.shorts.get_mut(&key) {
+        Some(mut existing) => existing.push(closing),
+        None => tracing::warn!(key=%key_repr, meta=?meta, "CloseShortWithoutOpen"),
+    }
 
     Ok(())
 }
 
 async fn record_initialize(
     client: Arc<Provider<Ws>>,
+    tconf: &SingleTrackerConfig,
     events: Arc<Events>,
     event: i_hyperdrive::InitializeFilter,
     meta: LogMeta,
+    gas: Option<GasCost>,
 ) -> Result<()> {
     tracing::debug!(
         block_num=%meta.block_number,
         provider=%event.provider,
-        lp_amount=%event.lp_amount/U256::exp10(18),
-        base_amount=%event.base_amount/U256::exp10(18),
+        lp_amount=%event.lp_amount/U256::exp10(tconf.base_decimals as usize),
+        base_amount=%event.base_amount/U256::exp10(tconf.base_decimals as usize),
         "InitializeLiquidity"
     );
 
@@ -289,6 +324,7 @@ async fn record_initialize(
         timestamp: block_timestamp,
         lp_amount: I256::from_raw(event.lp_amount),
         base_amount: I256::from_raw(event.base_amount),
+        gas,
     };
     let lp: Lp = vec![adding];
     events
@@ -302,15 +338,17 @@ async fn record_initialize(
 
 async fn record_add_liquidity(
     client: Arc<Provider<Ws>>,
+    tconf: &SingleTrackerConfig,
     events: Arc<Events>,
     event: i_hyperdrive::AddLiquidityFilter,
     meta: LogMeta,
+    gas: Option<GasCost>,
 ) -> Result<()> {
     tracing::debug!(
         block_num=%meta.block_number,
         provider=%event.provider,
-        lp_amount=%event.lp_amount/U256::exp10(18),
-        base_amount=%event.base_amount/U256::exp10(18),
+        lp_amount=%event.lp_amount/U256::exp10(tconf.base_decimals as usize),
+        base_amount=%event.base_amount/U256::exp10(tconf.base_decimals as usize),
         "AddLiquidity"
     );
 
@@ -327,6 +365,7 @@ async fn record_add_liquidity(
         timestamp: block_timestamp,
         lp_amount: I256::from_raw(event.lp_amount),
         base_amount: I256::from_raw(event.base_amount),
+        gas,
     };
     let lp: Lp = vec![adding];
     events
@@ -340,15 +379,17 @@ async fn record_add_liquidity(
 
 async fn record_remove_liquidity(
     client: Arc<Provider<Ws>>,
+    tconf: &SingleTrackerConfig,
     events: Arc<Events>,
     event: i_hyperdrive::RemoveLiquidityFilter,
     meta: LogMeta,
+    gas: Option<GasCost>,
 ) -> Result<()> {
     tracing::debug!(
         block_num=%meta.block_number,
         provider=%event.provider,
-        lp_amount=%event.lp_amount/U256::exp10(18),
-        base_amount=%event.base_amount/U256::exp10(18),
+        lp_amount=%event.lp_amount/U256::exp10(tconf.base_decimals as usize),
+        base_amount=%event.base_amount/U256::exp10(tconf.base_decimals as usize),
         "RemoveLiquidity"
     );
 
@@ -366,22 +407,457 @@ async fn record_remove_liquidity(
         timestamp: block_timestamp,
         lp_amount: -I256::from_raw(event.lp_amount),
         base_amount: -I256::from_raw(event.base_amount),
+        gas,
     };
-    events
-        .lps
-        .entry(key)
-        .and_modify(|existing| existing.push(removing))
-        .or_insert_with(|| {
-            panic!("RemoveLiquidity position doesn't exist: {}", key_repr);
-        });
+    match events.lps.get_mut(&key) {
+        Some(mut existing) => existing.push(removing),
+        None => tracing::warn!(key=%key_repr, meta=?meta, "RemoveLiquidityWithoutOpen"),
+    }
 
     Ok(())
 }
 
+///Address an event is recorded for.
+fn event_account(evt: &i_hyperdrive::IHyperdriveEvents) -> Option<H160> {
+    match evt {
+        i_hyperdrive::IHyperdriveEvents::OpenLongFilter(event) => Some(event.trader),
+        i_hyperdrive::IHyperdriveEvents::OpenShortFilter(event) => Some(event.trader),
+        i_hyperdrive::IHyperdriveEvents::CloseLongFilter(event) => Some(event.trader),
+        i_hyperdrive::IHyperdriveEvents::CloseShortFilter(event) => Some(event.trader),
+        i_hyperdrive::IHyperdriveEvents::InitializeFilter(event) => Some(event.provider),
+        i_hyperdrive::IHyperdriveEvents::AddLiquidityFilter(event) => Some(event.provider),
+        i_hyperdrive::IHyperdriveEvents::RemoveLiquidityFilter(event) => Some(event.provider),
+        _ => None,
+    }
+}
+
+///Gas left to charge per transaction, along with its sender. Emptied once charged, so that a
+///transaction emitting several events is charged once.
+type TxGasCosts = HashMap<H256, Option<(H160, GasCost)>>;
+
+///Gas left to charge for the transaction of an event. Receipts are fetched once per transaction.
+async fn fetch_tx_gas_cost<'a>(
+    client: Arc<Provider<Ws>>,
+    meta: &LogMeta,
+    tx_gas_costs: &'a mut TxGasCosts,
+) -> Result<&'a mut Option<(H160, GasCost)>> {
+    Ok(match tx_gas_costs.entry(meta.transaction_hash) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => {
+            let receipt = client
+                .get_transaction_receipt(meta.transaction_hash)
+                .await?;
+            let gas =
+                receipt.and_then(
+                    |receipt| match (receipt.gas_used, receipt.effective_gas_price) {
+                        (Some(gas_used), Some(effective_gas_price)) => Some((
+                            receipt.from,
+                            GasCost {
+                                gas_used,
+                                effective_gas_price,
+                                transaction_hash: Some(meta.transaction_hash),
+                            },
+                        )),
+                        _ => None,
+                    },
+                );
+            if gas.is_none() {
+                tracing::warn!(meta=?meta, "MissingGasCost");
+            }
+            entry.insert(gas)
+        }
+    })
+}
+
+///Gas of the transaction of an event, charged to `account` only if it sent the transaction and
+///was not charged it already.
+async fn fetch_gas_cost(
+    client: Arc<Provider<Ws>>,
+    account: H160,
+    meta: &LogMeta,
+    tx_gas_costs: &mut TxGasCosts,
+) -> Result<Option<GasCost>> {
+    let tx_gas_cost = fetch_tx_gas_cost(client, meta, tx_gas_costs).await?;
+    match *tx_gas_cost {
+        Some((sender, gas)) if sender == account => {
+            *tx_gas_cost = None;
+            Ok(Some(gas))
+        }
+        _ => Ok(None),
+    }
+}
+
+///Fees of a trade event against the pool state of the block before, none for other events.
+///Open long curve fees are in bonds and close fees in vault shares, all are converted to base.
+///
+///Trades earlier in the same block are missing from that state, which the pool only exposes per
+///block. Their followers are still estimated, with a `SameBlockTradeFees` warning.
+async fn estimate_trade_fees(
+    client: Arc<Provider<Ws>>,
+    tconf: &SingleTrackerConfig,
+    evt: &i_hyperdrive::IHyperdriveEvents,
+    meta: &LogMeta,
+    last_trade_block: &mut Option<U64>,
+) -> Result<Option<TradeFees>> {
+    use i_hyperdrive::IHyperdriveEvents::*;
+
+    if !matches!(
+        evt,
+        OpenLongFilter(_) | OpenShortFilter(_) | CloseLongFilter(_) | CloseShortFilter(_)
+    ) {
+        return Ok(None);
+    }
+    if last_trade_block.replace(meta.block_number) == Some(meta.block_number) {
+        tracing::warn!(meta=?meta, "SameBlockTradeFees");
+    }
+
+    let pool_info = tconf
+        .contract
+        .get_pool_info()
+        .block(meta.block_number - 1)
+        .call()
+        .await?
+        .to_wad(tconf.base_decimals);
+    let pool_config = tconf.pool_config.clone().to_wad(tconf.base_decimals);
+    let state = hyperdrive_math::State::new(pool_config, pool_info);
+    let vault_share_price = state.info.vault_share_price.normalized();
+    let block_timestamp = client
+        .get_block(meta.block_number)
+        .await?
+        .ok_or_else(|| eyre!("missing block {}", meta.block_number))?
+        .timestamp;
+
+    let (curve, flat) = match evt {
+        OpenLongFilter(event) => (
+            state
+                .open_long_curve_fees(event.base_amount.to_wad(tconf.base_decimals))
+                .normalized()
+                * state.calculate_spot_price().normalized(),
+            Decimal::ZERO,
+        ),
+        OpenShortFilter(event) => (
+            state
+                .open_short_curve_fee(event.bond_amount.to_wad(tconf.base_decimals))
+                .normalized(),
+            Decimal::ZERO,
+        ),
+        CloseLongFilter(event) => (
+            state
+                .close_long_curve_fee(
+                    event.bond_amount.to_wad(tconf.base_decimals),
+                    event.maturity_time,
+                    block_timestamp,
+                )
+                .normalized()
+                * vault_share_price,
+            state
+                .close_long_flat_fee(
+                    event.bond_amount.to_wad(tconf.base_decimals),
+                    event.maturity_time,
+                    block_timestamp,
+                )
+                .normalized()
+                * vault_share_price,
+        ),
+        CloseShortFilter(event) => (
+            state
+                .close_short_curve_fee(
+                    event.bond_amount.to_wad(tconf.base_decimals),
+                    event.maturity_time,
+                    block_timestamp,
+                )
+                .normalized()
+                * vault_share_price,
+            state
+                .close_short_flat_fee(
+                    event.bond_amount.to_wad(tconf.base_decimals),
+                    event.maturity_time,
+                    block_timestamp,
+                )
+                .normalized()
+                * vault_share_price,
+        ),
+        _ => return Ok(None),
+    };
+    let governance = (curve + flat) * state.config.fees.governance_lp.normalized();
+
+    let fees = TradeFees {
+        curve: curve.round_dp(DECIMAL_PRECISION),
+        flat: flat.round_dp(DECIMAL_PRECISION),
+        governance: governance.round_dp(DECIMAL_PRECISION),
+    };
+    tracing::debug!(fees=?fees, meta=?meta, "EstimatedTradeFees");
+
+    Ok(Some(fees))
+}
+
+///Address a router event is credited to, `account` itself for other events.
+async fn attribute_account(
+    client: Arc<Provider<Ws>>,
+    qconf: &AcqConfig,
+    account: H160,
+    destination: Option<H160>,
+    meta: &LogMeta,
+) -> Result<H160> {
+    let is_router = |address: &H160| qconf.routers.iter().any(|p| p.matches(address));
+    if !is_router(&account) {
+        return Ok(account);
+    }
+
+    let attributed = match (qconf.attribution, destination) {
+        (Attribution::Destination, Some(destination)) if !is_router(&destination) => destination,
+        _ => match client.get_transaction(meta.transaction_hash).await? {
+            Some(tx) => tx.from,
+            None => {
+                tracing::warn!(meta=?meta, "MissingRouterTransaction");
+                account
+            }
+        },
+    };
+
+    tracing::debug!(router=?account, attributed=?attributed, meta=?meta, "AttributingRouterEvent");
+
+    Ok(attributed)
+}
+
+///Credits events sent through a router to the player behind it.
+async fn attribute_event(
+    client: Arc<Provider<Ws>>,
+    qconf: &AcqConfig,
+    evt: i_hyperdrive::IHyperdriveEvents,
+    meta: &LogMeta,
+) -> Result<i_hyperdrive::IHyperdriveEvents> {
+    use i_hyperdrive::IHyperdriveEvents::*;
+
+    Ok(match evt {
+        OpenLongFilter(mut event) => {
+            event.trader = attribute_account(client, qconf, event.trader, None, meta).await?;
+            OpenLongFilter(event)
+        }
+        OpenShortFilter(mut event) => {
+            event.trader = attribute_account(client, qconf, event.trader, None, meta).await?;
+            OpenShortFilter(event)
+        }
+        CloseLongFilter(mut event) => {
+            event.trader =
+                attribute_account(client, qconf, event.trader, Some(event.destination), meta)
+                    .await?;
+            CloseLongFilter(event)
+        }
+        CloseShortFilter(mut event) => {
+            event.trader =
+                attribute_account(client, qconf, event.trader, Some(event.destination), meta)
+                    .await?;
+            CloseShortFilter(event)
+        }
+        InitializeFilter(mut event) => {
+            event.provider = attribute_account(client, qconf, event.provider, None, meta).await?;
+            InitializeFilter(event)
+        }
+        AddLiquidityFilter(mut event) => {
+            event.provider = attribute_account(client, qconf, event.provider, None, meta).await?;
+            AddLiquidityFilter(event)
+        }
+        RemoveLiquidityFilter(mut event) => {
+            event.provider =
+                attribute_account(client, qconf, event.provider, Some(event.destination), meta)
+                    .await?;
+            RemoveLiquidityFilter(event)
+        }
+        other => other,
+    })
+}
+
+///Account a close is credited to, among the attributed one, the players credited with the
+///router position it closes, and the holder itself for positions opened before it was configured
+///as a router. The first holding a positive balance is picked, else the first having opened the
+///position, else none.
+fn credit_close(
+    attributed: H160,
+    holder: H160,
+    owners: Vec<H160>,
+    balance: impl Fn(H160) -> Option<I256>,
+) -> Option<H160> {
+    let candidates: Vec<H160> = [attributed]
+        .into_iter()
+        .chain(owners)
+        .chain([holder])
+        .collect();
+    candidates
+        .iter()
+        .find(|account| balance(**account).is_some_and(|b| b > I256::zero()))
+        .or_else(|| {
+            candidates
+                .iter()
+                .find(|account| balance(**account).is_some())
+        })
+        .copied()
+}
+
+///Keeps opens and closes going through routers under the same position: records whom router
+///opens are credited to, and credits closes as per `credit_close`. Closes of positions never
+///opened are dropped with a warning.
+fn match_router_positions(
+    events: &Events,
+    raw_evt: &i_hyperdrive::IHyperdriveEvents,
+    evt: i_hyperdrive::IHyperdriveEvents,
+    meta: &LogMeta,
+) -> Option<i_hyperdrive::IHyperdriveEvents> {
+    use i_hyperdrive::IHyperdriveEvents::*;
+
+    let credit_owner = |owners: &mut Vec<H160>, owner: H160| {
+        if !owners.contains(&owner) {
+            owners.push(owner);
+        }
+    };
+    let bonds = |debits: &Vec<PositionDebit>| {
+        debits
+            .iter()
+            .fold(I256::zero(), |acc, debit| acc + debit.bond_amount)
+    };
+
+    match (raw_evt, evt) {
+        (OpenLongFilter(raw), OpenLongFilter(event)) => {
+            if raw.trader != event.trader {
+                let router_key = PositionKey {
+                    trader: raw.trader,
+                    maturity_time: raw.maturity_time,
+                };
+                credit_owner(
+                    &mut events.router_longs.entry(router_key).or_default(),
+                    event.trader,
+                );
+            }
+            Some(OpenLongFilter(event))
+        }
+        (OpenShortFilter(raw), OpenShortFilter(event)) => {
+            if raw.trader != event.trader {
+                let router_key = PositionKey {
+                    trader: raw.trader,
+                    maturity_time: raw.maturity_time,
+                };
+                credit_owner(
+                    &mut events.router_shorts.entry(router_key).or_default(),
+                    event.trader,
+                );
+            }
+            Some(OpenShortFilter(event))
+        }
+        (InitializeFilter(raw), InitializeFilter(event)) => {
+            if raw.provider != event.provider {
+                let router_key = LpKey {
+                    provider: raw.provider,
+                };
+                credit_owner(
+                    &mut events.router_lps.entry(router_key).or_default(),
+                    event.provider,
+                );
+            }
+            Some(InitializeFilter(event))
+        }
+        (AddLiquidityFilter(raw), AddLiquidityFilter(event)) => {
+            if raw.provider != event.provider {
+                let router_key = LpKey {
+                    provider: raw.provider,
+                };
+                credit_owner(
+                    &mut events.router_lps.entry(router_key).or_default(),
+                    event.provider,
+                );
+            }
+            Some(AddLiquidityFilter(event))
+        }
+        (CloseLongFilter(raw), CloseLongFilter(mut event)) => {
+            let router_key = PositionKey {
+                trader: raw.trader,
+                maturity_time: raw.maturity_time,
+            };
+            let owners = events
+                .router_longs
+                .get(&router_key)
+                .map(|owners| owners.clone())
+                .unwrap_or_default();
+            let balance = |trader: H160| {
+                let key = PositionKey {
+                    trader,
+                    maturity_time: event.maturity_time,
+                };
+                events.longs.get(&key).map(|long| bonds(&long))
+            };
+            match credit_close(event.trader, raw.trader, owners, balance) {
+                Some(trader) => {
+                    event.trader = trader;
+                    Some(CloseLongFilter(event))
+                }
+                None => {
+                    tracing::warn!(event=?event, meta=?meta, "CloseLongWithoutOpen");
+                    None
+                }
+            }
+        }
+        (CloseShortFilter(raw), CloseShortFilter(mut event)) => {
+            let router_key = PositionKey {
+                trader: raw.trader,
+                maturity_time: raw.maturity_time,
+            };
+            let owners = events
+                .router_shorts
+                .get(&router_key)
+                .map(|owners| owners.clone())
+                .unwrap_or_default();
+            let balance = |trader: H160| {
+                let key = PositionKey {
+                    trader,
+                    maturity_time: event.maturity_time,
+                };
+                events.shorts.get(&key).map(|short| bonds(&short))
+            };
+            match credit_close(event.trader, raw.trader, owners, balance) {
+                Some(trader) => {
+                    event.trader = trader;
+                    Some(CloseShortFilter(event))
+                }
+                None => {
+                    tracing::warn!(event=?event, meta=?meta, "CloseShortWithoutOpen");
+                    None
+                }
+            }
+        }
+        (RemoveLiquidityFilter(raw), RemoveLiquidityFilter(mut event)) => {
+            let router_key = LpKey {
+                provider: raw.provider,
+            };
+            let owners = events
+                .router_lps
+                .get(&router_key)
+                .map(|owners| owners.clone())
+                .unwrap_or_default();
+            let balance = |provider: H160| {
+                events.lps.get(&LpKey { provider }).map(|lp| {
+                    lp.iter()
+                        .fold(I256::zero(), |acc, debit| acc + debit.lp_amount)
+                })
+            };
+            match credit_close(event.provider, raw.provider, owners, balance) {
+                Some(provider) => {
+                    event.provider = provider;
+                    Some(RemoveLiquidityFilter(event))
+                }
+                None => {
+                    tracing::warn!(event=?event, meta=?meta, "RemoveLiquidityWithoutOpen");
+                    None
+                }
+            }
+        }
+        (_, evt) => Some(evt),
+    }
+}
+
 ///Loads events from page start (inclusive) to page end (**non inclusive**).
 async fn load_events_paginated(
     rconf: &RunConfig,
     tconf: &SingleTrackerConfig,
+    qconf: &AcqConfig,
     events: Arc<Events>,
     page_start_block: U64,
     page_end_block: U64,
@@ -393,16 +869,71 @@ async fn load_events_paginated(
         .from_block(page_start_block)
         .to_block(page_end_block - 1);
     let query = contract_events.query_with_meta().await?;
+    // Pages hold whole blocks, hence whole transactions.
+    let mut tx_gas_costs = TxGasCosts::new();
+    let mut dropped_txs: HashMap<H256, U64> = HashMap::new();
+    let mut last_trade_block: Option<U64> = None;
+
+    for (raw_evt, meta) in query {
+        let evt = if qconf.routers.is_empty() {
+            raw_evt.clone()
+        } else {
+            attribute_event(rconf.client.clone(), qconf, raw_evt.clone(), &meta).await?
+        };
+        // Router positions are matched whatever the current routers, as they may have changed
+        // since the opens were recorded.
+        let Some(evt) = match_router_positions(&events, &raw_evt, evt, &meta) else {
+            if qconf.fetch_receipts {
+                fetch_tx_gas_cost(rconf.client.clone(), &meta, &mut tx_gas_costs).await?;
+                dropped_txs.insert(meta.transaction_hash, meta.block_number);
+            }
+            continue;
+        };
+
+        let gas = match event_account(&evt).filter(|_| qconf.fetch_receipts) {
+            Some(account) => {
+                fetch_gas_cost(rconf.client.clone(), account, &meta, &mut tx_gas_costs).await?
+            }
+            None => None,
+        };
+
+        let fees = if qconf.estimate_fees {
+            estimate_trade_fees(
+                rconf.client.clone(),
+                tconf,
+                &evt,
+                &meta,
+                &mut last_trade_block,
+            )
+            .await?
+        } else {
+            None
+        };
 
-    for (evt, meta) in query {
         match evt.clone() {
             i_hyperdrive::IHyperdriveEvents::OpenLongFilter(event) => {
-                record_open_long(rconf.client.clone(), events.clone(), event, meta.clone()).await?;
+                record_open_long(
+                    rconf.client.clone(),
+                    tconf,
+                    events.clone(),
+                    event,
+                    meta.clone(),
+                    gas,
+                    fees,
+                )
+                .await?;
             }
             i_hyperdrive::IHyperdriveEvents::OpenShortFilter(event) => {
-                let short_key =
-                    record_open_short(rconf.client.clone(), events.clone(), event, meta.clone())
-                        .await?;
+                let short_key = record_open_short(
+                    rconf.client.clone(),
+                    tconf,
+                    events.clone(),
+                    event,
+                    meta.clone(),
+                    gas,
+                    fees,
+                )
+                .await?;
 
                 tracing::debug!(
                     short_key=?short_key,
@@ -420,24 +951,61 @@ async fn load_events_paginated(
                 .await?;
             }
             i_hyperdrive::IHyperdriveEvents::InitializeFilter(event) => {
-                record_initialize(rconf.client.clone(), events.clone(), event, meta.clone())
-                    .await?;
+                record_initialize(
+                    rconf.client.clone(),
+                    tconf,
+                    events.clone(),
+                    event,
+                    meta.clone(),
+                    gas,
+                )
+                .await?;
             }
             i_hyperdrive::IHyperdriveEvents::AddLiquidityFilter(event) => {
-                record_add_liquidity(rconf.client.clone(), events.clone(), event, meta.clone())
-                    .await?;
+                record_add_liquidity(
+                    rconf.client.clone(),
+                    tconf,
+                    events.clone(),
+                    event,
+                    meta.clone(),
+                    gas,
+                )
+                .await?;
             }
             i_hyperdrive::IHyperdriveEvents::CloseLongFilter(event) => {
-                record_close_long(rconf.client.clone(), events.clone(), event, meta.clone())
-                    .await?;
+                record_close_long(
+                    rconf.client.clone(),
+                    tconf,
+                    events.clone(),
+                    event,
+                    meta.clone(),
+                    gas,
+                    fees,
+                )
+                .await?;
             }
             i_hyperdrive::IHyperdriveEvents::CloseShortFilter(event) => {
-                record_close_short(rconf.client.clone(), events.clone(), event, meta.clone())
-                    .await?;
+                record_close_short(
+                    rconf.client.clone(),
+                    tconf,
+                    events.clone(),
+                    event,
+                    meta.clone(),
+                    gas,
+                    fees,
+                )
+                .await?;
             }
             i_hyperdrive::IHyperdriveEvents::RemoveLiquidityFilter(event) => {
-                record_remove_liquidity(rconf.client.clone(), events.clone(), event, meta.clone())
-                    .await?;
+                record_remove_liquidity(
+                    rconf.client.clone(),
+                    tconf,
+                    events.clone(),
+                    event,
+                    meta.clone(),
+                    gas,
+                )
+                .await?;
             }
             _ => (),
         }
@@ -445,10 +1013,36 @@ async fn load_events_paginated(
         tracing::debug!(meta=?meta.clone(), evt=?evt.clone(), "EndQueryEvent");
     }
 
+    // Dropped events leave their transaction gas uncharged unless another event paid for it.
+    for (transaction_hash, block_number) in dropped_txs {
+        let Some(Some((_, gas))) = tx_gas_costs.get(&transaction_hash) else {
+            continue;
+        };
+        let timestamp = rconf
+            .client
+            .get_block(block_number)
+            .await?
+            .ok_or_else(|| eyre!("missing block {}", block_number))?
+            .timestamp;
+        tracing::debug!(transaction_hash=?transaction_hash, gas=?gas, "UnattributedGas");
+        events.unattributed_gas.insert(
+            transaction_hash,
+            UnattributedGas {
+                block_number,
+                timestamp,
+                gas: *gas,
+            },
+        );
+    }
+
     Ok(())
 }
 
-pub async fn launch_acq(rconf: &RunConfig, tconf: &SingleTrackerConfig) -> Result<()> {
+pub async fn launch_acq(
+    rconf: &RunConfig,
+    tconf: &SingleTrackerConfig,
+    qconf: &AcqConfig,
+) -> Result<()> {
     let mut page_end_block_num: U64;
     let mut page_start_block_num: U64;
     let events: Arc<Events>;
@@ -468,6 +1062,7 @@ pub async fn launch_acq(rconf: &RunConfig, tconf: &SingleTrackerConfig) -> Resul
         load_events_paginated(
             rconf,
             tconf,
+            qconf,
             events.clone(),
             page_start_block_num,
             page_end_block_num,
@@ -485,10 +1080,7 @@ pub async fn launch_acq(rconf: &RunConfig, tconf: &SingleTrackerConfig) -> Resul
             events: events.to_serializable(),
         };
         let json_str = serde_json::to_string_pretty(&events_db)?;
-        let mut file = fs::File::create(format!(
-            "{}-{}.json",
-            tconf.hconf.pool_type, tconf.hconf.address
-        ))?;
+        let mut file = fs::File::create(eventsdb_filename(tconf.hconf))?;
         file.write_all(json_str.as_bytes())?;
 
         page_start_block_num += rconf.page_size;
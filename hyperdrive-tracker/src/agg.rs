@@ -1,21 +1,25 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs;
 use std::ops::AddAssign;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 
 use chrono::{DateTime, Duration};
 use csv::Writer;
 use dashmap::DashMap;
 use ethers::{
     providers::Middleware,
-    types::{H160, I256, U256, U64},
+    types::{H160, H256, I256, U256, U64},
 };
-use eyre::Result;
+use eyre::{bail, Result};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 
 use hyperdrive_wrappers::wrappers::ihyperdrive::i_hyperdrive;
 
 use crate::globals::*;
+use crate::ledger::*;
+use crate::manifest::*;
 use crate::types::*;
 use crate::utils::*;
 
@@ -31,22 +35,38 @@ struct LpCumulativeDebit {
     lp_amount: U256,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+///`pnl` is `realized_pnl + unrealized_pnl`, the realized part being that of the `ledger` closes.
+///`valuation` is the base amount the remaining bonds are valued at. `benchmark_pnl` is what the
+///same base debits would have earned held in the vault instead.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct PositionStatement {
     cumulative_debit: PositionCumulativeDebit,
+    valuation: Decimal,
     pnl: Decimal,
+    realized_pnl: Decimal,
+    unrealized_pnl: Decimal,
+    benchmark_pnl: Decimal,
+    ledger: LotLedger,
 }
 
 type PositionStatements = HashMap<PositionKey, PositionStatement>;
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct LpStatement {
     cumulative_debit: LpCumulativeDebit,
+    valuation: Decimal,
     pnl: Decimal,
+    realized_pnl: Decimal,
+    unrealized_pnl: Decimal,
+    benchmark_pnl: Decimal,
+    ledger: LotLedger,
 }
 
 type LpStatements = HashMap<LpKey, LpStatement>;
 
+///Long, short and LP statements of a pool, as of a same timestamp.
+type Statements = (PositionStatements, PositionStatements, LpStatements);
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 struct CumulativeDebits {
     long: I256,
@@ -108,46 +128,721 @@ impl AddAssign for Volume {
     }
 }
 
+///Normalized volume left out by the minimum holding rule.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct ChurnVolume {
+    long: Decimal,
+    short: Decimal,
+    lp: Decimal,
+}
+
+impl AddAssign for ChurnVolume {
+    fn add_assign(&mut self, other: Self) {
+        self.long += other.long;
+        self.short += other.short;
+        self.lp += other.lp;
+    }
+}
+
+///LP PnL over a period split by driver, normalized. `counterparty` is what remains of the LP PnL
+///once fees and idle liquidity yield are accounted for, mostly the PnL of taking the other side of
+///longs and shorts.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+struct LpAttribution {
+    fees: Decimal,
+    idle_yield: Decimal,
+    counterparty: Decimal,
+    ///Some trades of the period lack fee estimates, leaving the split unknown.
+    #[serde(default)]
+    missing_fees: bool,
+}
+
+impl AddAssign for LpAttribution {
+    fn add_assign(&mut self, other: Self) {
+        self.fees += other.fees;
+        self.idle_yield += other.idle_yield;
+        self.counterparty += other.counterparty;
+        self.missing_fees |= other.missing_fees;
+    }
+}
+
+impl LpAttribution {
+    ///None when fees are missing, so as not to book them as counterparty.
+    fn known(&self) -> Option<&Self> {
+        (!self.missing_fees).then_some(self)
+    }
+}
+
+///Normalized base amounts converted to USD.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct UsdAmounts {
+    long: Decimal,
+    short: Decimal,
+    lp: Decimal,
+}
+
+impl AddAssign for UsdAmounts {
+    fn add_assign(&mut self, other: Self) {
+        self.long += other.long;
+        self.short += other.short;
+        self.lp += other.lp;
+    }
+}
+
+///Volumes at the price of their trades' time, PnLs and TVLs at the price of the period end. A
+///price missing for any of them voids them all.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct UsdValues {
+    volume: UsdAmounts,
+    pnl: UsdAmounts,
+    tvl: UsdAmounts,
+    missing_price: bool,
+}
+
+impl AddAssign for UsdValues {
+    fn add_assign(&mut self, other: Self) {
+        self.volume += other.volume;
+        self.pnl += other.pnl;
+        self.tvl += other.tvl;
+        self.missing_price |= other.missing_price;
+    }
+}
+
+///Time-weighted average balances over a period. Normalized, like `PnL`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct TimeWeighted {
+    long: Decimal,
+    short: Decimal,
+    lp: Decimal,
+}
+
+impl AddAssign for TimeWeighted {
+    fn add_assign(&mut self, other: Self) {
+        self.long += other.long;
+        self.short += other.short;
+        self.lp += other.lp;
+    }
+}
+
+///Base debit integrated over time since the first debit, in base-seconds.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+struct Capital {
+    area: Decimal,
+    since: Option<U256>,
+}
+
+impl AddAssign for Capital {
+    fn add_assign(&mut self, other: Self) {
+        self.area += other.area;
+        self.since = match (self.since, other.since) {
+            (Some(a), Some(b)) => Some(U256::min(a, b)),
+            (a, b) => a.or(b),
+        };
+    }
+}
+
+impl Capital {
+    ///Average capital deployed from `since` to `at_timestamp`.
+    fn time_weighted(&self, at_timestamp: U256) -> Decimal {
+        match self.since {
+            Some(since) if since < at_timestamp => (self.area
+                / Decimal::from((at_timestamp - since).as_u64()))
+            .round_dp(DECIMAL_PRECISION),
+            _ => Decimal::ZERO,
+        }
+    }
+
+    ///Return on time-weighted capital, and that return annualized without compounding. None when
+    ///no capital was deployed.
+    fn returns(&self, pnl: Decimal, at_timestamp: U256) -> Option<(Decimal, Decimal)> {
+        let capital = self.time_weighted(at_timestamp);
+        if capital <= Decimal::ZERO {
+            return None;
+        }
+        let roc = pnl / capital;
+        let elapsed = Decimal::from((at_timestamp - self.since?).as_u64());
+        let apr = roc * Decimal::from(SECONDS_PER_YEAR) / elapsed;
+        Some((
+            roc.round_dp(DECIMAL_PRECISION),
+            apr.round_dp(DECIMAL_PRECISION),
+        ))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct UserCapital {
+    long: Capital,
+    short: Capital,
+    lp: Capital,
+}
+
+impl AddAssign for UserCapital {
+    fn add_assign(&mut self, other: Self) {
+        self.long += other.long;
+        self.short += other.short;
+        self.lp += other.lp;
+    }
+}
+
+impl UserCapital {
+    fn total(&self) -> Capital {
+        let mut total = self.long;
+        total += self.short;
+        total += self.lp;
+        total
+    }
+}
+
+///Base-weighted sum of the fixed rates locked by opens, to average them over volume.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+struct LockedRate {
+    weighted_rate: Decimal,
+    volume: Decimal,
+}
+
+impl AddAssign for LockedRate {
+    fn add_assign(&mut self, other: Self) {
+        self.weighted_rate += other.weighted_rate;
+        self.volume += other.volume;
+    }
+}
+
+impl LockedRate {
+    fn lock(&mut self, fixed_rate: Decimal, base_amount: Decimal) {
+        self.weighted_rate += fixed_rate * base_amount;
+        self.volume += base_amount;
+    }
+
+    fn average(&self) -> Option<Decimal> {
+        if self.volume.is_zero() {
+            None
+        } else {
+            Some((self.weighted_rate / self.volume).round_dp(DECIMAL_PRECISION))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct LockedRates {
+    long: LockedRate,
+    short: LockedRate,
+}
+
+impl AddAssign for LockedRates {
+    fn add_assign(&mut self, other: Self) {
+        self.long += other.long;
+        self.short += other.short;
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 struct UserAgg {
     action_count: ActionCount,
     volume: Volume,
+    churn_volume: ChurnVolume,
+    ///Seconds each lot closed within the period was held for.
+    holding_times: Vec<u64>,
     pnl: PnL,
+    realized_pnl: PnL,
+    unrealized_pnl: PnL,
+    ///PnL of holding the vault token with the same base debits.
+    benchmark_pnl: PnL,
     base_cumulative_debit: CumulativeDebits,
+    ///Time-weighted `base_cumulative_debit`.
+    twa_base_debit: TimeWeighted,
+    ///Time-weighted bonds for longs and shorts, LP shares for LPs.
+    twa_amount: TimeWeighted,
+    capital: UserCapital,
+    locked_rates: LockedRates,
+    ///Normalized ETH paid for the gas of the period transactions.
+    gas_spent: Decimal,
+    ///Estimated trading fees paid over the period.
+    fees_paid: Decimal,
+    ///Pool LP PnL drivers over the period, pro rata of the time-weighted LP shares held.
+    lp_attribution: LpAttribution,
+    usd: UsdValues,
+    ///Wallets merged into this aggregate, when aggregating per participant.
+    addresses: BTreeSet<H160>,
+    ///Some amount of the pool period overflowed `Decimal` and counts as zero.
+    #[serde(default)]
+    decimal_overflow: bool,
+}
+
+impl UserAgg {
+    fn member_addresses(&self) -> String {
+        self.addresses
+            .iter()
+            .map(|address| format!("{:?}", address))
+            .collect::<Vec<String>>()
+            .join(";")
+    }
+}
+
+impl AddAssign for UserAgg {
+    fn add_assign(&mut self, other: Self) {
+        self.action_count += other.action_count;
+        self.volume += other.volume;
+        self.churn_volume += other.churn_volume;
+        self.holding_times.extend(other.holding_times);
+        self.pnl += other.pnl;
+        self.realized_pnl += other.realized_pnl;
+        self.unrealized_pnl += other.unrealized_pnl;
+        self.benchmark_pnl += other.benchmark_pnl;
+        self.base_cumulative_debit += other.base_cumulative_debit;
+        self.twa_base_debit += other.twa_base_debit;
+        self.twa_amount += other.twa_amount;
+        self.capital += other.capital;
+        self.locked_rates += other.locked_rates;
+        self.gas_spent += other.gas_spent;
+        self.fees_paid += other.fees_paid;
+        self.lp_attribution += other.lp_attribution;
+        self.usd += other.usd;
+        self.addresses.extend(other.addresses);
+        self.decimal_overflow |= other.decimal_overflow;
+    }
+}
+
+///Ordered by address so that output rows are deterministic.
+type UsersAggs = BTreeMap<H160, UserAgg>;
+
+///A lot close along with the position it belongs to. LPs have no maturity.
+#[derive(Debug, Clone)]
+struct PositionLotClose {
+    position_type: PositionType,
+    user_address: H160,
+    maturity_time: Option<U256>,
+    close: LotClose,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum PositionStatus {
+    Open,
+    Matured,
+    Closed,
+}
+
+///A position statement at period end, flattened for export. `amount` is bonds or LP shares.
+#[derive(Debug, Clone)]
+struct PositionSummary {
+    position_type: PositionType,
+    user_address: H160,
+    maturity_time: Option<U256>,
+    status: PositionStatus,
+    amount: Decimal,
+    base_debit: Decimal,
+    valuation: Decimal,
+    pnl: Decimal,
+    realized_pnl: Decimal,
+    unrealized_pnl: Decimal,
+    benchmark_pnl: Decimal,
 }
 
-type UsersAggs = HashMap<H160, UserAgg>;
+///Pool-wide activity over a period and pool state at its end.
+#[derive(Debug, Clone, Default)]
+struct PoolMetrics {
+    trade_count: ActionCount,
+    volume: Volume,
+    ///Base flowing into the pool, negative when more went out.
+    net_base_flow: CumulativeDebits,
+    active_traders: usize,
+    longs_outstanding: U256,
+    shorts_outstanding: U256,
+    share_reserves: U256,
+    lp_share_price: U256,
+    vault_share_price: U256,
+    spot_rate: Decimal,
+    ///Estimated trading fees collected over the period, net of the governance part. Allocated to
+    ///LP rows as `lp_pnl_fees`.
+    fees_lps: Decimal,
+    fees_governance: Decimal,
+    ///Some trades of the period have no fee estimate.
+    missing_fees: bool,
+    lp_attribution: LpAttribution,
+    ///Normalized ETH paid for the gas of period transactions whose events were all dropped.
+    gas_unattributed: Decimal,
+    ///Some amount of the period overflowed `Decimal` and counts as zero.
+    decimal_overflow: bool,
+}
+
+///An open executed within the period, with the fixed rate it locked.
+#[derive(Debug, Clone)]
+struct TradeRate {
+    position_type: PositionType,
+    user_address: H160,
+    maturity_time: U256,
+    block_number: U64,
+    timestamp: U256,
+    base_amount: Decimal,
+    bond_amount: Decimal,
+    fixed_rate: Decimal,
+}
+
+///Everything calculated for one pool over one period.
+#[derive(Debug, Clone)]
+struct PoolPeriodAggs {
+    metrics: PoolMetrics,
+    users_aggs: UsersAggs,
+    lot_closes: Vec<PositionLotClose>,
+    positions: Vec<PositionSummary>,
+    trade_rates: Vec<TradeRate>,
+}
+
+pub const ROWS_FILENAME: &str = "rows.csv";
+pub const POOLS_FILENAME: &str = "pools.csv";
+pub const IDENTITY_CONFLICTS_FILENAME: &str = "identity_conflicts.csv";
+pub const UNREGISTERED_FILENAME: &str = "unregistered.csv";
+///Row address of the aggregate of every address rejected by `aconf.address_filter`.
+pub const EXCLUDED_ADDRESS: H160 = H160::zero();
+
+///Agg command line options shaping the output, as recorded in the manifest.
+pub const AGG_OPTIONS: [&str; 14] = [
+    "group_by",
+    "valuation",
+    "cost_basis",
+    "positions_out",
+    "rates_out",
+    "lots_out",
+    "min_capital",
+    "min_holding",
+    "holding_weighting",
+    "identities",
+    "allow",
+    "deny",
+    "participants",
+    "prices",
+];
+///Options naming input files, `allow` and `deny` only when not a comma-separated list.
+const AGG_FILE_OPTIONS: [&str; 5] = ["identities", "allow", "deny", "participants", "prices"];
+
+impl AggConfig {
+    ///Parses the options named in `AGG_OPTIONS`, reading the files they point to.
+    pub fn from_options(out_dir: PathBuf, options: &BTreeMap<String, String>) -> Result<Self> {
+        let option = |name: &str| options.get(name).map(String::as_str);
+
+        let mut aconf = AggConfig {
+            out_dir,
+            group_by: GroupBy::PoolType,
+            valuation: Valuation::Maturity,
+            cost_basis: CostBasis::AverageCost,
+            positions_out: option("positions_out").map(str::to_string),
+            rates_out: option("rates_out").map(str::to_string),
+            lots_out: option("lots_out").map(str::to_string),
+            min_capital: None,
+            min_holding: None,
+            holding_weighting: HoldingWeighting::Exclude,
+            identities: None,
+            address_filter: AddressFilter::default(),
+            participants: None,
+            prices: None,
+            options: options.clone(),
+        };
+
+        if let Some(gb_str) = option("group_by") {
+            aconf.group_by = gb_str.parse()?;
+        }
+        if let Some(v_str) = option("valuation") {
+            aconf.valuation = v_str.parse()?;
+        }
+        if let Some(cb_str) = option("cost_basis") {
+            aconf.cost_basis = cb_str.parse()?;
+        }
+        if let Some(mc_str) = option("min_capital") {
+            aconf.min_capital = Some(mc_str.parse()?);
+        }
+        if let Some(mh_str) = option("min_holding") {
+            aconf.min_holding = Some(mh_str.parse()?);
+        }
+        if let Some(hw_str) = option("holding_weighting") {
+            aconf.holding_weighting = hw_str.parse()?;
+        }
+        if let Some(ids_path) = option("identities") {
+            aconf.identities = Some(read_identities(Path::new(ids_path))?);
+        }
+        if let Some(allow_str) = option("allow") {
+            aconf.address_filter.allow = read_address_patterns(allow_str)?;
+        }
+        if let Some(deny_str) = option("deny") {
+            aconf.address_filter.deny = read_address_patterns(deny_str)?;
+        }
+        if let Some(participants_path) = option("participants") {
+            aconf.participants = Some(read_participants(Path::new(participants_path))?);
+        }
+        if let Some(prices_path) = option("prices") {
+            aconf.prices = Some(read_prices(Path::new(prices_path))?);
+        }
+
+        Ok(aconf)
+    }
+
+    ///Files the options point to, hashed as inputs along with the events DBs.
+    pub fn input_filenames(&self) -> Vec<&str> {
+        AGG_FILE_OPTIONS
+            .iter()
+            .filter_map(|name| self.options.get(*name))
+            .map(String::as_str)
+            .filter(|path| Path::new(path).is_file())
+            .collect()
+    }
+
+    ///Every file a run writes in `out_dir`, besides the manifest.
+    pub fn output_filenames(&self) -> Vec<&str> {
+        let mut filenames = vec![ROWS_FILENAME, POOLS_FILENAME];
+        filenames.extend(self.positions_out.as_deref());
+        filenames.extend(self.rates_out.as_deref());
+        filenames.extend(self.lots_out.as_deref());
+        if self.identities.is_some() {
+            filenames.push(IDENTITY_CONFLICTS_FILENAME);
+        }
+        if self.participants.is_some() {
+            filenames.push(UNREGISTERED_FILENAME);
+        }
+        filenames
+    }
+}
+
+///Activity of an address absent from the participants, over a period.
+#[derive(Serialize)]
+struct UnregisteredCsvRecord {
+    timestamp: String,
+    block_number: u64,
+    pool_type: String,
+    pool_address: Option<H160>,
+    base_token: Option<H160>,
+    user_address: H160,
+    member_addresses: String,
+    action_count: usize,
+    volume: String,
+}
+
+#[derive(Serialize)]
+struct IdentityConflictCsvRecord {
+    address: H160,
+    participants: String,
+}
 
 #[derive(Serialize)]
 struct CsvRecord {
     timestamp: String,
     block_number: u64,
+    ///Unique per pool group, which `pool_type` alone is not when grouping by address.
+    pool_group: String,
     pool_type: String,
+    pool_address: Option<H160>,
+    base_token: Option<H160>,
     user_address: H160,
+    excluded: bool,
+    participant: Option<String>,
+    member_addresses: Option<String>,
     action_count_longs: usize,
     action_count_shorts: usize,
     action_count_lps: usize,
     volume_longs: String,
     volume_shorts: String,
     volume_lps: String,
+    holding_median_seconds: Option<u64>,
+    holding_max_seconds: Option<u64>,
     pnl_longs: String,
     pnl_shorts: String,
     pnl_lps: String,
     tvl_longs: String,
     tvl_shorts: String,
     tvl_lps: String,
+    twa_tvl_longs: String,
+    twa_tvl_shorts: String,
+    twa_tvl_lps: String,
+    twa_bonds_longs: String,
+    twa_bonds_shorts: String,
+    twa_lp_shares: String,
+    roc_longs: Option<String>,
+    roc_shorts: Option<String>,
+    roc_lps: Option<String>,
+    roc: Option<String>,
+    apr_longs: Option<String>,
+    apr_shorts: Option<String>,
+    apr_lps: Option<String>,
+    apr: Option<String>,
+    below_min_capital: bool,
+    avg_fixed_rate_longs: Option<String>,
+    avg_fixed_rate_shorts: Option<String>,
+    realized_pnl_longs: String,
+    realized_pnl_shorts: String,
+    realized_pnl_lps: String,
+    unrealized_pnl_longs: String,
+    unrealized_pnl_shorts: String,
+    unrealized_pnl_lps: String,
+    excess_pnl_longs: String,
+    excess_pnl_shorts: String,
+    excess_pnl_lps: String,
+    gas_spent: String,
+    fees_paid: String,
+    lp_pnl_fees: Option<String>,
+    lp_pnl_idle_yield: Option<String>,
+    lp_pnl_counterparty: Option<String>,
+    volume_usd_longs: Option<String>,
+    volume_usd_shorts: Option<String>,
+    volume_usd_lps: Option<String>,
+    pnl_usd_longs: Option<String>,
+    pnl_usd_shorts: Option<String>,
+    pnl_usd_lps: Option<String>,
+    tvl_usd_longs: Option<String>,
+    tvl_usd_shorts: Option<String>,
+    tvl_usd_lps: Option<String>,
+    ///Only for pool groups denominated in ETH, in which gas is paid.
+    pnl_net_of_gas: Option<String>,
+    decimal_overflow: bool,
 }
 
-///Calculates balances at timestamp and position PnLs as if closed at time of maturity.
+#[derive(Serialize)]
+struct PoolCsvRecord {
+    timestamp: String,
+    block_number: u64,
+    pool_type: String,
+    pool_address: H160,
+    trade_count_longs: usize,
+    trade_count_shorts: usize,
+    trade_count_lps: usize,
+    volume_longs: String,
+    volume_shorts: String,
+    volume_lps: String,
+    net_base_flow_longs: String,
+    net_base_flow_shorts: String,
+    net_base_flow_lps: String,
+    active_traders: usize,
+    longs_outstanding: String,
+    shorts_outstanding: String,
+    share_reserves: String,
+    lp_share_price: String,
+    vault_share_price: String,
+    spot_rate: String,
+    fees_lps: String,
+    fees_governance: String,
+    lp_pnl_fees: Option<String>,
+    lp_pnl_idle_yield: Option<String>,
+    lp_pnl_counterparty: Option<String>,
+    gas_unattributed: String,
+    decimal_overflow: bool,
+}
+
+#[derive(Serialize)]
+struct PositionCsvRecord {
+    timestamp: String,
+    block_number: u64,
+    pool_type: String,
+    pool_address: H160,
+    position_type: PositionType,
+    user_address: H160,
+    maturity_time: Option<String>,
+    status: PositionStatus,
+    amount: String,
+    cumulative_base_debit: String,
+    valuation: String,
+    pnl: String,
+    realized_pnl: String,
+    unrealized_pnl: String,
+    benchmark_pnl: String,
+    excess_pnl: String,
+}
+
+#[derive(Serialize)]
+struct TradeRateCsvRecord {
+    time: String,
+    block_number: u64,
+    pool_type: String,
+    pool_address: H160,
+    position_type: PositionType,
+    user_address: H160,
+    maturity_time: String,
+    base_amount: String,
+    bond_amount: String,
+    fixed_rate: String,
+}
+
+#[derive(Serialize)]
+struct LotCloseCsvRecord {
+    timestamp: String,
+    pool_type: String,
+    pool_address: H160,
+    position_type: PositionType,
+    user_address: H160,
+    maturity_time: Option<String>,
+    lot_block_number: u64,
+    lot_time: String,
+    close_block_number: u64,
+    close_time: String,
+    amount: String,
+    cost: String,
+    proceeds: String,
+    pnl: String,
+    holding_seconds: u64,
+}
+
+///Vault share prices read at the blocks of the debits, and that of the state valued against.
+struct SharePriceCurve<'a> {
+    vault_share_prices: &'a BTreeMap<U64, U256>,
+    at_share_price: Decimal,
+}
+
+impl<'a> SharePriceCurve<'a> {
+    fn new(vault_share_prices: &'a BTreeMap<U64, U256>, at_share_price: U256) -> Self {
+        SharePriceCurve {
+            vault_share_prices,
+            at_share_price: at_share_price.normalized(),
+        }
+    }
+
+    ///Normalized price at the end of the block, or of the latest block read before it.
+    fn price_at(&self, block_num: U64) -> Decimal {
+        self.vault_share_prices
+            .range(..=block_num)
+            .next_back()
+            .map_or(Decimal::ZERO, |(_, price)| price.normalized())
+    }
+
+    ///PnL at `at_timestamp` of depositing each base debit into the vault when it happened and
+    ///withdrawing each credit likewise.
+    fn holding_pnl<I>(&self, debits: I, at_timestamp: U256) -> Decimal
+    where
+        I: IntoIterator<Item = LedgerDebit>,
+    {
+        let at_price = self.at_share_price;
+        debits
+            .into_iter()
+            .filter(|debit| debit.timestamp < at_timestamp)
+            .map(|debit| {
+                let price = self.price_at(debit.block_number);
+                if price.is_zero() {
+                    Decimal::ZERO
+                } else {
+                    debit.base_amount.normalized() * (at_price / price - Decimal::ONE)
+                }
+            })
+            .sum::<Decimal>()
+            .round_dp(DECIMAL_PRECISION)
+    }
+}
+
+///Balance left once closes are netted, zero with `warn` called when more was closed than opened.
+///Such over-closes are realized at zero cost by the ledger.
+fn non_negative_balance(balance: I256, warn: impl FnOnce()) -> U256 {
+    balance.try_into().unwrap_or_else(|_| {
+        warn();
+        U256::zero()
+    })
+}
+
+///Calculates balances at timestamp and position PnLs as if closed at time of maturity or, with
+///`Valuation::Market`, as if closed at timestamp against the pool state at that time.
 fn calc_pnls(
+    aconf: &AggConfig,
     sevents: &SerializableEvents,
     hyperdrive_state: hyperdrive_math::State,
     at_timestamp: U256,
-) -> (
-    HashMap<PositionKey, PositionStatement>,
-    HashMap<PositionKey, PositionStatement>,
-    HashMap<LpKey, LpStatement>,
-) {
+    vault_share_prices: &BTreeMap<U64, U256>,
+) -> Statements {
+    let share_price_curve =
+        SharePriceCurve::new(vault_share_prices, hyperdrive_state.info.vault_share_price);
+
     // [PERF] We could build these cumulatively in Debit objects.
     let longs_cumul_debits: HashMap<PositionKey, PositionCumulativeDebit> = sevents
         .longs
@@ -170,9 +865,9 @@ fn calc_pnls(
                 key,
                 PositionCumulativeDebit {
                     base_amount: base_cumul_debit,
-                    bond_amount: bond_cumul_credit
-                        .try_into()
-                        .expect("Negative long bond balance"),
+                    bond_amount: non_negative_balance(bond_cumul_credit, || {
+                        tracing::warn!(key=?key, balance=%bond_cumul_credit, "NegativeLongBondBalance")
+                    }),
                 },
             )
         })
@@ -199,9 +894,9 @@ fn calc_pnls(
                 key,
                 PositionCumulativeDebit {
                     base_amount: base_cumul_debit,
-                    bond_amount: bond_cumul_credit
-                        .try_into()
-                        .expect("Negative short bond balance"),
+                    bond_amount: non_negative_balance(bond_cumul_credit, || {
+                        tracing::warn!(key=?key, balance=%bond_cumul_credit, "NegativeShortBondBalance")
+                    }),
                 },
             )
         })
@@ -227,7 +922,10 @@ fn calc_pnls(
                 key,
                 LpCumulativeDebit {
                     base_amount: base_cumul_debit,
-                    lp_amount: lp_shares_credit.try_into().expect("Negative LP balance"),
+                    lp_amount: non_negative_balance(
+                        lp_shares_credit,
+                        || tracing::warn!(key=?key, balance=%lp_shares_credit, "NegativeLpBalance"),
+                    ),
                 },
             )
         })
@@ -253,10 +951,14 @@ fn calc_pnls(
                     "CalculatingCloseLong"
                 );
 
+                let close_time = match aconf.valuation {
+                    Valuation::Maturity => long_key.maturity_time,
+                    Valuation::Market => at_timestamp,
+                };
                 let calculated_close_shares = hyperdrive_state.calculate_close_long(
                     cumulative_debit.bond_amount,
                     long_key.maturity_time,
-                    long_key.maturity_time,
+                    close_time,
                 );
 
                 // Knowing `calculate_close_long` returns vault share amounts:
@@ -273,10 +975,28 @@ fn calc_pnls(
             };
 
             let cumulative_base_debit = cumulative_debit.base_amount.normalized();
+            let pnl = calculated_close_base_amount - cumulative_base_debit;
+            let ledger = LotLedger::from_debits(
+                sevents.longs[long_key]
+                    .iter()
+                    .filter(|debit| debit.timestamp < at_timestamp)
+                    .map(LedgerDebit::from),
+                aconf.cost_basis,
+            );
+            let realized_pnl = ledger.realized_pnl();
+            let benchmark_pnl = share_price_curve.holding_pnl(
+                sevents.longs[long_key].iter().map(LedgerDebit::from),
+                at_timestamp,
+            );
 
             let pos_statement = PositionStatement {
                 cumulative_debit: *cumulative_debit,
-                pnl: calculated_close_base_amount - cumulative_base_debit,
+                valuation: calculated_close_base_amount,
+                pnl,
+                realized_pnl,
+                unrealized_pnl: pnl - realized_pnl,
+                benchmark_pnl,
+                ledger,
             };
 
             (*long_key, pos_statement)
@@ -317,11 +1037,21 @@ fn calc_pnls(
                 maturity_checkpoint_time,
                 sevents.share_prices
             );
-            let maturity_or_current_share_price = sevents
-                .share_prices
-                .get(&maturity_checkpoint_time)
-                .expect(maturity_share_errmsg)
-                .price;
+            let recorded_maturity_share_price = || {
+                sevents
+                    .share_prices
+                    .get(&maturity_checkpoint_time)
+                    .expect(maturity_share_errmsg)
+                    .price
+            };
+            // Marking an open short to market closes it at the current share price.
+            let (maturity_or_current_share_price, close_time) = match aconf.valuation {
+                Valuation::Maturity => (recorded_maturity_share_price(), short_key.maturity_time),
+                Valuation::Market if short_key.maturity_time > at_timestamp => {
+                    (hyperdrive_state.info.vault_share_price, at_timestamp)
+                }
+                Valuation::Market => (recorded_maturity_share_price(), at_timestamp),
+            };
 
             tracing::debug!(
                 short_key=?short_key,
@@ -349,7 +1079,7 @@ fn calc_pnls(
                     maturity_or_current_share_price,
                     // This argument is maturity time, wheter already happened or not:
                     short_key.maturity_time,
-                    short_key.maturity_time,
+                    close_time,
                 );
 
                 calculated_maturity_shares.normalized()
@@ -367,10 +1097,28 @@ fn calc_pnls(
             };
 
             let cumulative_base_debit = cumulative_debit.base_amount.normalized();
+            let pnl = calculated_maturity_base_amount - cumulative_base_debit;
+            let ledger = LotLedger::from_debits(
+                sevents.shorts[short_key]
+                    .iter()
+                    .filter(|debit| debit.timestamp < at_timestamp)
+                    .map(LedgerDebit::from),
+                aconf.cost_basis,
+            );
+            let realized_pnl = ledger.realized_pnl();
+            let benchmark_pnl = share_price_curve.holding_pnl(
+                sevents.shorts[short_key].iter().map(LedgerDebit::from),
+                at_timestamp,
+            );
 
             let pos_statement = PositionStatement {
                 cumulative_debit: *cumulative_debit,
-                pnl: calculated_maturity_base_amount - cumulative_base_debit,
+                valuation: calculated_maturity_base_amount,
+                pnl,
+                realized_pnl,
+                unrealized_pnl: pnl - realized_pnl,
+                benchmark_pnl,
+                ledger,
             };
 
             (*short_key, pos_statement)
@@ -393,10 +1141,28 @@ fn calc_pnls(
             let lp_base_amount = cumulative_debit.lp_amount.normalized()
                 * hyperdrive_state.info.lp_share_price.normalized();
             let cumulative_base_debit = cumulative_debit.base_amount.normalized();
+            let pnl = lp_base_amount - cumulative_base_debit;
+            let ledger = LotLedger::from_debits(
+                sevents.lps[lp_key]
+                    .iter()
+                    .filter(|debit| debit.timestamp < at_timestamp)
+                    .map(LedgerDebit::from),
+                aconf.cost_basis,
+            );
+            let realized_pnl = ledger.realized_pnl();
+            let benchmark_pnl = share_price_curve.holding_pnl(
+                sevents.lps[lp_key].iter().map(LedgerDebit::from),
+                at_timestamp,
+            );
 
             let lp_statement = LpStatement {
                 cumulative_debit: *cumulative_debit,
-                pnl: lp_base_amount - cumulative_base_debit,
+                valuation: lp_base_amount,
+                pnl,
+                realized_pnl,
+                unrealized_pnl: pnl - realized_pnl,
+                benchmark_pnl,
+                ledger,
             };
 
             (*lp_key, lp_statement)
@@ -406,15 +1172,525 @@ fn calc_pnls(
     (longs_pnls, shorts_pnls, lps_pnls)
 }
 
+///Time-weighted averages over [start, end) of the running base debit and bond (or LP share)
+///balances of chronologically ordered debits.
+fn calc_time_weighted_balances<I>(
+    debits: I,
+    start_timestamp: U256,
+    end_timestamp: U256,
+) -> (Decimal, Decimal)
+where
+    I: IntoIterator<Item = LedgerDebit>,
+{
+    let (base_area, amount_area) = calc_balance_areas(debits, start_timestamp, end_timestamp);
+    let duration = Decimal::from((end_timestamp - start_timestamp).as_u64());
+    (
+        (base_area / duration).round_dp(DECIMAL_PRECISION),
+        (amount_area / duration).round_dp(DECIMAL_PRECISION),
+    )
+}
+
+///Capital deployed by chronologically ordered debits, from the first one until `end_timestamp`.
+fn calc_capital<I>(debits: I, end_timestamp: U256) -> Capital
+where
+    I: IntoIterator<Item = LedgerDebit>,
+{
+    let debits: Vec<LedgerDebit> = debits
+        .into_iter()
+        .filter(|debit| debit.timestamp < end_timestamp)
+        .collect();
+    match debits.first() {
+        Some(first) => Capital {
+            area: calc_balance_areas(debits.clone(), first.timestamp, end_timestamp).0,
+            since: Some(first.timestamp),
+        },
+        None => Capital::default(),
+    }
+}
+
+///Integrals over [start, end) of the running base debit and bond (or LP share) balances, in
+///normalized amount-seconds.
+fn calc_balance_areas<I>(
+    debits: I,
+    start_timestamp: U256,
+    end_timestamp: U256,
+) -> (Decimal, Decimal)
+where
+    I: IntoIterator<Item = LedgerDebit>,
+{
+    let mut base_balance = Decimal::ZERO;
+    let mut amount_balance = Decimal::ZERO;
+    let mut base_area = Decimal::ZERO;
+    let mut amount_area = Decimal::ZERO;
+    let mut cursor = start_timestamp;
+
+    for debit in debits
+        .into_iter()
+        .filter(|debit| debit.timestamp < end_timestamp)
+    {
+        if debit.timestamp > cursor {
+            let elapsed = Decimal::from((debit.timestamp - cursor).as_u64());
+            base_area += base_balance * elapsed;
+            amount_area += amount_balance * elapsed;
+            cursor = debit.timestamp;
+        }
+        base_balance += debit.base_amount.normalized();
+        amount_balance += debit.amount.normalized();
+    }
+    let elapsed = Decimal::from((end_timestamp - cursor).as_u64());
+    base_area += base_balance * elapsed;
+    amount_area += amount_balance * elapsed;
+
+    (base_area, amount_area)
+}
+
+///Fixed rate locked by an opening debit, calculated for debits acquired before it was recorded.
+fn opening_fixed_rate(
+    position_type: PositionType,
+    debit: &PositionDebit,
+    position_duration: U256,
+) -> Option<Decimal> {
+    if debit.bond_amount <= I256::zero() {
+        return None;
+    }
+    debit.fixed_rate.or_else(|| match position_type {
+        PositionType::Long => {
+            calc_long_fixed_rate(debit.base_amount, debit.bond_amount, position_duration)
+        }
+        PositionType::Short => {
+            calc_short_fixed_rate(debit.base_amount, debit.bond_amount, position_duration)
+        }
+        PositionType::Lp => None,
+    })
+}
+
+///Opens within the period along with their fixed rates, in block order.
+fn collect_period_trade_rates(
+    sevents: &SerializableEvents,
+    position_duration: U256,
+    start_timestamp: U256,
+    end_timestamp: U256,
+) -> Vec<TradeRate> {
+    let mut trade_rates: Vec<TradeRate> = Vec::new();
+    for (position_type, positions) in [
+        (PositionType::Long, &sevents.longs),
+        (PositionType::Short, &sevents.shorts),
+    ] {
+        for (key, debits) in positions.iter() {
+            for debit in debits.iter().filter(|debit| {
+                start_timestamp <= debit.timestamp && debit.timestamp < end_timestamp
+            }) {
+                if let Some(fixed_rate) =
+                    opening_fixed_rate(position_type, debit, position_duration)
+                {
+                    trade_rates.push(TradeRate {
+                        position_type,
+                        user_address: key.trader,
+                        maturity_time: key.maturity_time,
+                        block_number: debit.block_number,
+                        timestamp: debit.timestamp,
+                        base_amount: debit.base_amount.normalized(),
+                        bond_amount: debit.bond_amount.normalized(),
+                        fixed_rate,
+                    });
+                }
+            }
+        }
+    }
+
+    trade_rates.sort_by_key(|tr| {
+        (
+            tr.block_number,
+            tr.position_type,
+            tr.user_address,
+            tr.maturity_time,
+        )
+    });
+    trade_rates
+}
+
+fn calc_pool_metrics(
+    sevents: &SerializableEvents,
+    hyperdrive_state: &hyperdrive_math::State,
+    start_timestamp: U256,
+    end_timestamp: U256,
+) -> PoolMetrics {
+    let in_period = |timestamp: U256| start_timestamp <= timestamp && timestamp < end_timestamp;
+    let mut metrics = PoolMetrics::default();
+    let mut active_traders: HashSet<H160> = HashSet::new();
+
+    for (key, long) in sevents.longs.iter() {
+        for debit in long.iter().filter(|debit| in_period(debit.timestamp)) {
+            metrics.trade_count.long += 1;
+            metrics.volume.long += debit.base_amount.abs();
+            metrics.net_base_flow.long += debit.base_amount;
+            match debit.fees {
+                Some(fees) => {
+                    metrics.fees_lps += fees.total() - fees.governance;
+                    metrics.fees_governance += fees.governance;
+                }
+                None => metrics.missing_fees = true,
+            }
+            active_traders.insert(key.trader);
+        }
+    }
+    for (key, short) in sevents.shorts.iter() {
+        for debit in short.iter().filter(|debit| in_period(debit.timestamp)) {
+            metrics.trade_count.short += 1;
+            metrics.volume.short += debit.base_amount.abs();
+            metrics.net_base_flow.short += debit.base_amount;
+            match debit.fees {
+                Some(fees) => {
+                    metrics.fees_lps += fees.total() - fees.governance;
+                    metrics.fees_governance += fees.governance;
+                }
+                None => metrics.missing_fees = true,
+            }
+            active_traders.insert(key.trader);
+        }
+    }
+    for (key, lp) in sevents.lps.iter() {
+        for debit in lp.iter().filter(|debit| in_period(debit.timestamp)) {
+            metrics.trade_count.lp += 1;
+            metrics.volume.lp += debit.base_amount.abs();
+            metrics.net_base_flow.lp += debit.base_amount;
+            active_traders.insert(key.provider);
+        }
+    }
+    metrics.gas_unattributed = sevents
+        .unattributed_gas
+        .values()
+        .filter(|unattributed| in_period(unattributed.timestamp))
+        .map(|unattributed| unattributed.gas.fee().normalized())
+        .sum();
+
+    metrics.active_traders = active_traders.len();
+    metrics.longs_outstanding = hyperdrive_state.info.longs_outstanding;
+    metrics.shorts_outstanding = hyperdrive_state.info.shorts_outstanding;
+    metrics.share_reserves = hyperdrive_state.info.share_reserves;
+    metrics.lp_share_price = hyperdrive_state.info.lp_share_price;
+    metrics.vault_share_price = hyperdrive_state.info.vault_share_price;
+    metrics.spot_rate = hyperdrive_state.calculate_spot_rate().normalized();
+
+    metrics
+}
+
+///Converts the period aggregates of a pool to USD. Volumes are converted trade by trade, then
+///reduced by the minimum holding rule in the same proportion as base volumes.
+fn apply_usd_prices(
+    prices: &PriceBook,
+    base_token: H160,
+    sevents: &SerializableEvents,
+    users_aggs: &mut UsersAggs,
+    start_timestamp: U256,
+    end_timestamp: U256,
+) {
+    let in_period = |timestamp: U256| start_timestamp <= timestamp && timestamp < end_timestamp;
+    let mut raw_volumes: BTreeMap<H160, UsdAmounts> = BTreeMap::new();
+    let mut missing_prices: BTreeSet<H160> = BTreeSet::new();
+    let mut volume_usd = |address: H160, timestamp: U256, base_amount: I256| match prices
+        .price_at(base_token, timestamp)
+    {
+        Some(price) => Some(base_amount.normalized().abs() * price),
+        None => {
+            missing_prices.insert(address);
+            None
+        }
+    };
+
+    for (key, long) in sevents.longs.iter() {
+        for debit in long.iter().filter(|debit| in_period(debit.timestamp)) {
+            if let Some(usd) = volume_usd(key.trader, debit.timestamp, debit.base_amount) {
+                raw_volumes.entry(key.trader).or_default().long += usd;
+            }
+        }
+    }
+    for (key, short) in sevents.shorts.iter() {
+        for debit in short.iter().filter(|debit| in_period(debit.timestamp)) {
+            if let Some(usd) = volume_usd(key.trader, debit.timestamp, debit.base_amount) {
+                raw_volumes.entry(key.trader).or_default().short += usd;
+            }
+        }
+    }
+    for (key, lp) in sevents.lps.iter() {
+        for debit in lp.iter().filter(|debit| in_period(debit.timestamp)) {
+            if let Some(usd) = volume_usd(key.provider, debit.timestamp, debit.base_amount) {
+                raw_volumes.entry(key.provider).or_default().lp += usd;
+            }
+        }
+    }
+
+    let end_price = prices.price_at(base_token, end_timestamp);
+    let net_of_churn = |usd: Decimal, volume: I256, churn: Decimal| {
+        let volume = volume.normalized();
+        if volume.is_zero() {
+            usd
+        } else {
+            (usd * (volume - churn) / volume).round_dp(DECIMAL_PRECISION)
+        }
+    };
+    for (address, agg) in users_aggs.iter_mut() {
+        let raw_volume = raw_volumes.remove(address).unwrap_or_default();
+        agg.usd.volume = UsdAmounts {
+            long: net_of_churn(raw_volume.long, agg.volume.long, agg.churn_volume.long),
+            short: net_of_churn(raw_volume.short, agg.volume.short, agg.churn_volume.short),
+            lp: net_of_churn(raw_volume.lp, agg.volume.lp, agg.churn_volume.lp),
+        };
+        agg.usd.missing_price = missing_prices.contains(address) || end_price.is_none();
+        if let Some(price) = end_price {
+            agg.usd.pnl = UsdAmounts {
+                long: (agg.pnl.long * price).round_dp(DECIMAL_PRECISION),
+                short: (agg.pnl.short * price).round_dp(DECIMAL_PRECISION),
+                lp: (agg.pnl.lp * price).round_dp(DECIMAL_PRECISION),
+            };
+            agg.usd.tvl = UsdAmounts {
+                long: (agg.base_cumulative_debit.long.normalized() * price)
+                    .round_dp(DECIMAL_PRECISION),
+                short: (agg.base_cumulative_debit.short.normalized() * price)
+                    .round_dp(DECIMAL_PRECISION),
+                lp: (agg.base_cumulative_debit.lp.normalized() * price).round_dp(DECIMAL_PRECISION),
+            };
+        }
+    }
+
+    if !missing_prices.is_empty() || end_price.is_none() {
+        tracing::warn!(base_token=?base_token, end_timestamp=?end_timestamp, "MissingUsdPrice");
+    }
+}
+
+///Vault shares of the pool not backing longs, above the minimum reserves.
+fn idle_shares(state: &hyperdrive_math::State) -> Decimal {
+    let vault_share_price = state.info.vault_share_price.normalized();
+    if vault_share_price.is_zero() {
+        return Decimal::ZERO;
+    }
+    let idle = state.info.share_reserves.normalized()
+        - state.info.longs_outstanding.normalized() / vault_share_price
+        - state.config.minimum_share_reserves.normalized();
+    Decimal::max(idle, Decimal::ZERO)
+}
+
+///Splits the LP PnL change of every user over a period, from `start_lp_pnls` to their current LP
+///PnL: the LP fees of `metrics` and the yield of the vault shares idle at the start go to each
+///user pro rata to their time-weighted LP shares over the time-weighted total, the remainder of
+///the change being counterparty. Returns the pool total, which LP shares locked at initialization
+///and withdrawal shares have no part in.
+fn attribute_lp_pnl(
+    start_state: &hyperdrive_math::State,
+    metrics: &PoolMetrics,
+    start_lp_pnls: &HashMap<H160, Decimal>,
+    users_aggs: &mut UsersAggs,
+) -> LpAttribution {
+    let idle_yield = idle_shares(start_state)
+        * (metrics.vault_share_price.normalized()
+            - start_state.info.vault_share_price.normalized());
+    let twa_lp_supply = users_aggs
+        .values()
+        .map(|agg| agg.twa_amount.lp)
+        .sum::<Decimal>();
+
+    let mut total = LpAttribution {
+        missing_fees: metrics.missing_fees,
+        ..Default::default()
+    };
+    for (address, agg) in users_aggs.iter_mut() {
+        let start_lp_pnl = start_lp_pnls.get(address).copied().unwrap_or_default();
+        let share = if twa_lp_supply.is_zero() {
+            Decimal::ZERO
+        } else {
+            agg.twa_amount.lp / twa_lp_supply
+        };
+        let fees = (metrics.fees_lps * share).round_dp(DECIMAL_PRECISION);
+        let idle_yield = (idle_yield * share).round_dp(DECIMAL_PRECISION);
+        agg.lp_attribution = LpAttribution {
+            fees,
+            idle_yield,
+            counterparty: agg.pnl.lp - start_lp_pnl - fees - idle_yield,
+            missing_fees: metrics.missing_fees,
+        };
+        total += agg.lp_attribution;
+    }
+    total
+}
+
+///Lot closes happening within the period, sorted by position.
+fn collect_period_lot_closes(
+    long_statements: &PositionStatements,
+    short_statements: &PositionStatements,
+    lp_statements: &LpStatements,
+    start_timestamp: U256,
+    end_timestamp: U256,
+) -> Vec<PositionLotClose> {
+    let in_period = |close: &&LotClose| {
+        start_timestamp <= close.close_timestamp && close.close_timestamp < end_timestamp
+    };
+
+    let mut lot_closes: Vec<PositionLotClose> = Vec::new();
+    for (position_type, statements) in [
+        (PositionType::Long, long_statements),
+        (PositionType::Short, short_statements),
+    ] {
+        for (key, stmt) in statements.iter() {
+            lot_closes.extend(stmt.ledger.closes.iter().filter(in_period).map(|close| {
+                PositionLotClose {
+                    position_type,
+                    user_address: key.trader,
+                    maturity_time: Some(key.maturity_time),
+                    close: *close,
+                }
+            }));
+        }
+    }
+    for (key, stmt) in lp_statements.iter() {
+        lot_closes.extend(stmt.ledger.closes.iter().filter(in_period).map(|close| {
+            PositionLotClose {
+                position_type: PositionType::Lp,
+                user_address: key.provider,
+                maturity_time: None,
+                close: *close,
+            }
+        }));
+    }
+
+    // Stable, so lots of a same close keep their matching order.
+    lot_closes.sort_by_key(|plc| {
+        (
+            plc.position_type,
+            plc.user_address,
+            plc.maturity_time,
+            plc.close.close_block_number,
+        )
+    });
+    lot_closes
+}
+
+///Statements of every position opened before `at_timestamp`, sorted by position.
+fn collect_positions(
+    long_statements: &PositionStatements,
+    short_statements: &PositionStatements,
+    lp_statements: &LpStatements,
+    at_timestamp: U256,
+) -> Vec<PositionSummary> {
+    let opened = |ledger: &LotLedger| !(ledger.lots.is_empty() && ledger.closes.is_empty());
+
+    let mut positions: Vec<PositionSummary> = Vec::new();
+    for (position_type, statements) in [
+        (PositionType::Long, long_statements),
+        (PositionType::Short, short_statements),
+    ] {
+        positions.extend(
+            statements
+                .iter()
+                .filter(|(_, stmt)| opened(&stmt.ledger))
+                .map(|(key, stmt)| PositionSummary {
+                    position_type,
+                    user_address: key.trader,
+                    maturity_time: Some(key.maturity_time),
+                    status: if stmt.cumulative_debit.bond_amount.is_zero() {
+                        PositionStatus::Closed
+                    } else if key.maturity_time <= at_timestamp {
+                        PositionStatus::Matured
+                    } else {
+                        PositionStatus::Open
+                    },
+                    amount: stmt.cumulative_debit.bond_amount.normalized(),
+                    base_debit: stmt.cumulative_debit.base_amount.normalized(),
+                    valuation: stmt.valuation,
+                    pnl: stmt.pnl,
+                    realized_pnl: stmt.realized_pnl,
+                    unrealized_pnl: stmt.unrealized_pnl,
+                    benchmark_pnl: stmt.benchmark_pnl,
+                }),
+        );
+    }
+    positions.extend(
+        lp_statements
+            .iter()
+            .filter(|(_, stmt)| opened(&stmt.ledger))
+            .map(|(key, stmt)| PositionSummary {
+                position_type: PositionType::Lp,
+                user_address: key.provider,
+                maturity_time: None,
+                status: if stmt.cumulative_debit.lp_amount.is_zero() {
+                    PositionStatus::Closed
+                } else {
+                    PositionStatus::Open
+                },
+                amount: stmt.cumulative_debit.lp_amount.normalized(),
+                base_debit: stmt.cumulative_debit.base_amount.normalized(),
+                valuation: stmt.valuation,
+                pnl: stmt.pnl,
+                realized_pnl: stmt.realized_pnl,
+                unrealized_pnl: stmt.unrealized_pnl,
+                benchmark_pnl: stmt.benchmark_pnl,
+            }),
+    );
+
+    positions.sort_by_key(|ps| (ps.position_type, ps.user_address, ps.maturity_time));
+    positions
+}
+
+///Weight of a lot close in volume, 1 once held for the minimum holding time.
+fn holding_weight(aconf: &AggConfig, close: &LotClose) -> Decimal {
+    let holding_seconds = close.holding_seconds();
+    match aconf.min_holding {
+        Some(min_holding) if holding_seconds < min_holding => match aconf.holding_weighting {
+            HoldingWeighting::Exclude => Decimal::ZERO,
+            HoldingWeighting::ProRata => {
+                Decimal::from(holding_seconds) / Decimal::from(min_holding)
+            }
+        },
+        _ => Decimal::ONE,
+    }
+}
+
+///Volume of the period closes of a ledger left out by the minimum holding rule, that of the
+///lots they close included when opened within the period too. Also returns their holding times.
+fn calc_churn<'a, I>(
+    aconf: &AggConfig,
+    closes: I,
+    start_timestamp: U256,
+    end_timestamp: U256,
+) -> (Decimal, Vec<u64>)
+where
+    I: IntoIterator<Item = &'a LotClose>,
+{
+    let mut churn_volume = Decimal::ZERO;
+    let mut holding_times = Vec::new();
+    for close in closes.into_iter().filter(|close| {
+        start_timestamp <= close.close_timestamp && close.close_timestamp < end_timestamp
+    }) {
+        holding_times.push(close.holding_seconds());
+        let excluded = Decimal::ONE - holding_weight(aconf, close);
+        churn_volume += excluded * close.proceeds.abs();
+        if start_timestamp <= close.lot_timestamp {
+            churn_volume += excluded * close.cost.abs();
+        }
+    }
+    (churn_volume.round_dp(DECIMAL_PRECISION), holding_times)
+}
+
+fn median(values: &[u64]) -> Option<u64> {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+    let mid = sorted.len() / 2;
+    match sorted.len() {
+        0 => None,
+        len if len % 2 == 0 => Some((sorted[mid - 1] + sorted[mid]) / 2),
+        _ => Some(sorted[mid]),
+    }
+}
+
 fn aggregate_per_user_over_period(
+    aconf: &AggConfig,
     sevents: &SerializableEvents,
-    long_statements: PositionStatements,
-    short_statements: PositionStatements,
-    lp_statements: LpStatements,
+    position_duration: U256,
+    statements: Statements,
     start_timestamp: U256,
     end_timestamp: U256,
 ) -> UsersAggs {
-    let mut users_aggs: UsersAggs = HashMap::new();
+    let (long_statements, short_statements, lp_statements) = statements;
+    let mut users_aggs = UsersAggs::new();
 
     for (long_key, long) in sevents.longs.iter() {
         let filtered_entries: Vec<_> = long
@@ -423,8 +1699,35 @@ fn aggregate_per_user_over_period(
                 (start_timestamp <= debit.timestamp) && (debit.timestamp < end_timestamp)
             })
             .collect();
+        let (twa_base_debit, twa_bonds) = calc_time_weighted_balances(
+            long.iter().map(LedgerDebit::from),
+            start_timestamp,
+            end_timestamp,
+        );
         let agg = users_aggs.entry(long_key.trader).or_default();
         agg.action_count.long += filtered_entries.len();
+        agg.twa_base_debit.long += twa_base_debit;
+        agg.twa_amount.long += twa_bonds;
+        agg.capital.long += calc_capital(long.iter().map(LedgerDebit::from), end_timestamp);
+        agg.fees_paid += filtered_entries
+            .iter()
+            .filter_map(|debit| debit.fees)
+            .map(|fees| fees.total())
+            .sum::<Decimal>();
+        agg.gas_spent += filtered_entries
+            .iter()
+            .filter_map(|debit| debit.gas)
+            .map(|gas| gas.fee().normalized())
+            .sum::<Decimal>();
+        for debit in filtered_entries.iter() {
+            if let Some(fixed_rate) =
+                opening_fixed_rate(PositionType::Long, debit, position_duration)
+            {
+                agg.locked_rates
+                    .long
+                    .lock(fixed_rate, debit.base_amount.normalized());
+            }
+        }
         agg.volume.long += filtered_entries
             .iter()
             .map(|debit| {
@@ -448,8 +1751,35 @@ fn aggregate_per_user_over_period(
             .iter()
             .filter(|debit| start_timestamp <= debit.timestamp && debit.timestamp < end_timestamp)
             .collect();
+        let (twa_base_debit, twa_bonds) = calc_time_weighted_balances(
+            short.iter().map(LedgerDebit::from),
+            start_timestamp,
+            end_timestamp,
+        );
         let agg = users_aggs.entry(short_key.trader).or_default();
         agg.action_count.short += filtered_entries.len();
+        agg.twa_base_debit.short += twa_base_debit;
+        agg.twa_amount.short += twa_bonds;
+        agg.capital.short += calc_capital(short.iter().map(LedgerDebit::from), end_timestamp);
+        agg.fees_paid += filtered_entries
+            .iter()
+            .filter_map(|debit| debit.fees)
+            .map(|fees| fees.total())
+            .sum::<Decimal>();
+        agg.gas_spent += filtered_entries
+            .iter()
+            .filter_map(|debit| debit.gas)
+            .map(|gas| gas.fee().normalized())
+            .sum::<Decimal>();
+        for debit in filtered_entries.iter() {
+            if let Some(fixed_rate) =
+                opening_fixed_rate(PositionType::Short, debit, position_duration)
+            {
+                agg.locked_rates
+                    .short
+                    .lock(fixed_rate, debit.base_amount.normalized());
+            }
+        }
         agg.volume.short += filtered_entries
             .iter()
             .map(|debit| {
@@ -466,8 +1796,21 @@ fn aggregate_per_user_over_period(
             .iter()
             .filter(|debit| start_timestamp <= debit.timestamp && debit.timestamp < end_timestamp)
             .collect();
+        let (twa_base_debit, twa_lp_shares) = calc_time_weighted_balances(
+            lp.iter().map(LedgerDebit::from),
+            start_timestamp,
+            end_timestamp,
+        );
         let agg = users_aggs.entry(lp_key.provider).or_default();
         agg.action_count.lp += filtered_entries.len();
+        agg.twa_base_debit.lp += twa_base_debit;
+        agg.twa_amount.lp += twa_lp_shares;
+        agg.capital.lp += calc_capital(lp.iter().map(LedgerDebit::from), end_timestamp);
+        agg.gas_spent += filtered_entries
+            .iter()
+            .filter_map(|debit| debit.gas)
+            .map(|gas| gas.fee().normalized())
+            .sum::<Decimal>();
         agg.volume.lp += filtered_entries
             .iter()
             .map(|debit| {
@@ -482,44 +1825,166 @@ fn aggregate_per_user_over_period(
 
     for (long_key_ref, position_stmt_ref) in long_statements.iter() {
         let agg = users_aggs.entry(long_key_ref.trader).or_default();
+        let (churn_volume, holding_times) = calc_churn(
+            aconf,
+            &position_stmt_ref.ledger.closes,
+            start_timestamp,
+            end_timestamp,
+        );
+        agg.churn_volume.long += churn_volume;
+        agg.holding_times.extend(holding_times);
         agg.pnl.long += position_stmt_ref.pnl;
+        agg.realized_pnl.long += position_stmt_ref.realized_pnl;
+        agg.unrealized_pnl.long += position_stmt_ref.unrealized_pnl;
+        agg.benchmark_pnl.long += position_stmt_ref.benchmark_pnl;
         agg.base_cumulative_debit.long += position_stmt_ref.cumulative_debit.base_amount;
     }
     for (short_key_ref, position_stmt_ref) in short_statements.iter() {
         let agg = users_aggs.entry(short_key_ref.trader).or_default();
+        let (churn_volume, holding_times) = calc_churn(
+            aconf,
+            &position_stmt_ref.ledger.closes,
+            start_timestamp,
+            end_timestamp,
+        );
+        agg.churn_volume.short += churn_volume;
+        agg.holding_times.extend(holding_times);
         agg.pnl.short += position_stmt_ref.pnl;
+        agg.realized_pnl.short += position_stmt_ref.realized_pnl;
+        agg.unrealized_pnl.short += position_stmt_ref.unrealized_pnl;
+        agg.benchmark_pnl.short += position_stmt_ref.benchmark_pnl;
         agg.base_cumulative_debit.short += position_stmt_ref.cumulative_debit.base_amount;
     }
     for (lp_key_ref, position_stmt_ref) in lp_statements.iter() {
         let agg = users_aggs.entry(lp_key_ref.provider).or_default();
+        let (churn_volume, holding_times) = calc_churn(
+            aconf,
+            &position_stmt_ref.ledger.closes,
+            start_timestamp,
+            end_timestamp,
+        );
+        agg.churn_volume.lp += churn_volume;
+        agg.holding_times.extend(holding_times);
         agg.pnl.lp += position_stmt_ref.pnl;
+        agg.realized_pnl.lp += position_stmt_ref.realized_pnl;
+        agg.unrealized_pnl.lp += position_stmt_ref.unrealized_pnl;
+        agg.benchmark_pnl.lp += position_stmt_ref.benchmark_pnl;
         agg.base_cumulative_debit.lp += position_stmt_ref.cumulative_debit.base_amount;
     }
 
     users_aggs
 }
 
+///Bounds of an aggregation period, as timestamps and the blocks found for them.
+struct Period {
+    start: U256,
+    start_block_num: U64,
+    end: U256,
+    end_block_num: U64,
+}
+
+///Reads the vault share price at the period bounds and at every block a debit happened in up to
+///the period end, skipping blocks read in earlier periods.
+async fn read_vault_share_prices(
+    tconf: &SingleTrackerConfig,
+    sevents: &SerializableEvents,
+    period: &Period,
+    vault_share_prices: &mut BTreeMap<U64, U256>,
+) -> Result<()> {
+    let debit_block_nums = sevents
+        .longs
+        .values()
+        .chain(sevents.shorts.values())
+        .flatten()
+        .map(|debit| debit.block_number)
+        .chain(
+            sevents
+                .lps
+                .values()
+                .flatten()
+                .map(|debit| debit.block_number),
+        );
+    let block_nums: BTreeSet<U64> = debit_block_nums
+        .chain([period.start_block_num, period.end_block_num])
+        .filter(|block_num| *block_num <= period.end_block_num)
+        .filter(|block_num| !vault_share_prices.contains_key(block_num))
+        .collect();
+
+    tracing::debug!(count = block_nums.len(), "ReadingVaultSharePrices");
+
+    for block_num in block_nums {
+        let pool_info = tconf
+            .contract
+            .get_pool_info()
+            .block(block_num)
+            .call()
+            .await?;
+        vault_share_prices.insert(block_num, pool_info.vault_share_price);
+    }
+    Ok(())
+}
+
 async fn calc_period_aggs(
+    aconf: &AggConfig,
     tconf: &SingleTrackerConfig,
     sevents: &SerializableEvents,
-    period_start: U256,
-    period_end_block_num: U64,
-    period_end: U256,
-) -> Result<UsersAggs> {
+    period: &Period,
+    vault_share_prices: &mut BTreeMap<U64, U256>,
+) -> Result<PoolPeriodAggs> {
+    let (period_start, period_end) = (period.start, period.end);
+    let (period_start_block_num, period_end_block_num) =
+        (period.start_block_num, period.end_block_num);
+    read_vault_share_prices(tconf, sevents, period, vault_share_prices).await?;
+
     let pool_info = tconf
         .contract
         .get_pool_info()
         .block(period_end_block_num)
         .call()
-        .await?;
-    let hyperdrive_state = hyperdrive_math::State::new(tconf.pool_config.clone(), pool_info);
+        .await?
+        .to_wad(tconf.base_decimals);
+    let pool_config = tconf.pool_config.clone().to_wad(tconf.base_decimals);
+    let hyperdrive_state = hyperdrive_math::State::new(pool_config.clone(), pool_info);
+
+    let start_pool_info = tconf
+        .contract
+        .get_pool_info()
+        .block(period_start_block_num)
+        .call()
+        .await?
+        .to_wad(tconf.base_decimals);
+    let start_state = hyperdrive_math::State::new(pool_config, start_pool_info);
+
+    let mut metrics = calc_pool_metrics(sevents, &hyperdrive_state, period_start, period_end);
+    let position_duration = hyperdrive_state.config.position_duration;
+    let trade_rates =
+        collect_period_trade_rates(sevents, position_duration, period_start, period_end);
 
     tracing::info!(
         hyperdrive_state=?hyperdrive_state,
+        metrics=?metrics,
         "CalculatingPeriodPnLs"
     );
 
-    let (longs_stmts, shorts_stmts, lps_stmts) = calc_pnls(sevents, hyperdrive_state, period_end);
+    let (longs_stmts, shorts_stmts, lps_stmts) = calc_pnls(
+        aconf,
+        sevents,
+        hyperdrive_state,
+        period_end,
+        vault_share_prices,
+    );
+    // LP PnLs are cumulative, their change over the period is what gets attributed.
+    let (_, _, start_lps_stmts) = calc_pnls(
+        aconf,
+        sevents,
+        start_state.clone(),
+        period_start,
+        vault_share_prices,
+    );
+    let mut start_lp_pnls: HashMap<H160, Decimal> = HashMap::new();
+    for (lp_key, stmt) in start_lps_stmts.iter() {
+        *start_lp_pnls.entry(lp_key.provider).or_default() += stmt.pnl;
+    }
 
     tracing::info!(
         long_stmts_count = longs_stmts.len(),
@@ -528,25 +1993,66 @@ async fn calc_period_aggs(
         "AggregatingPerUserOverPeriod"
     );
 
-    Ok(aggregate_per_user_over_period(
+    let lot_closes = collect_period_lot_closes(
+        &longs_stmts,
+        &shorts_stmts,
+        &lps_stmts,
+        period_start,
+        period_end,
+    );
+
+    let positions = collect_positions(&longs_stmts, &shorts_stmts, &lps_stmts, period_end);
+
+    let mut users_aggs = aggregate_per_user_over_period(
+        aconf,
         sevents,
-        longs_stmts,
-        shorts_stmts,
-        lps_stmts,
+        position_duration,
+        (longs_stmts, shorts_stmts, lps_stmts),
         period_start,
         period_end,
-    ))
+    );
+
+    if let Some(prices) = aconf.prices.as_ref() {
+        apply_usd_prices(
+            prices,
+            tconf.pool_config.base_token,
+            sevents,
+            &mut users_aggs,
+            period_start,
+            period_end,
+        );
+    }
+
+    metrics.lp_attribution =
+        attribute_lp_pnl(&start_state, &metrics, &start_lp_pnls, &mut users_aggs);
+
+    Ok(PoolPeriodAggs {
+        metrics,
+        users_aggs,
+        lot_closes,
+        positions,
+        trade_rates,
+    })
 }
 
 async fn get_hyperdrive_aggs(
     rconf: &RunConfig,
+    aconf: &AggConfig,
     tconf: &SingleTrackerConfig,
     sevents: &SerializableEvents,
     period_start: U256,
     period_end: U256,
-) -> Result<UsersAggs> {
-    // The PnL part doesn't need to know about `period_start` as PnLs and balances are
-    // statements that we calculate at `period_end`.
+    vault_share_prices: &mut BTreeMap<U64, U256>,
+) -> Result<PoolPeriodAggs> {
+    // PnLs and balances are statements calculated at `period_end`, the start block only serves
+    // the LP PnL attribution.
+    let period_start_block_num = find_block_by_timestamp(
+        rconf.client.clone(),
+        period_start.as_u64(),
+        tconf.hconf.deploy_block_num,
+        rconf.end_block_num,
+    )
+    .await?;
     let period_end_block_num = find_block_by_timestamp(
         rconf.client.clone(),
         period_end.as_u64(),
@@ -558,36 +2064,105 @@ async fn get_hyperdrive_aggs(
 
     tracing::info!(tconf=?tconf, "CalculatingPeriodAggs");
 
-    let users_aggs = calc_period_aggs(
-        tconf,
-        sevents,
-        period_start,
-        period_end_block_num,
-        period_end,
-    )
-    .await?;
+    let period = Period {
+        start: period_start,
+        start_block_num: period_start_block_num,
+        end: period_end,
+        end_block_num: period_end_block_num,
+    };
+    let pool_aggs = calc_period_aggs(aconf, tconf, sevents, &period, vault_share_prices).await?;
 
-    Ok(users_aggs)
+    Ok(pool_aggs)
 }
 
-fn group_users_aggs_by_address(usersaggs_list: &[UsersAggs]) -> UsersAggs {
+///With identities, wallets of a same participant are merged under its primary address. Filtered
+///out addresses are all merged under `EXCLUDED_ADDRESS`, so that rows still sum to pool totals.
+fn group_users_aggs_by_address(usersaggs_list: &[UsersAggs], aconf: &AggConfig) -> UsersAggs {
     usersaggs_list
         .iter()
         .fold(UsersAggs::new(), |mut acc, usersaggs| {
             for (address, user_agg) in usersaggs {
-                let entry = acc.entry(*address).or_default();
-                entry.action_count += user_agg.action_count.clone();
-                entry.volume += user_agg.volume.clone();
-                entry.pnl += user_agg.pnl.clone();
-                entry.base_cumulative_debit += user_agg.base_cumulative_debit.clone();
+                let key = if !aconf.address_filter.accepts(address) {
+                    EXCLUDED_ADDRESS
+                } else {
+                    aconf
+                        .identities
+                        .as_ref()
+                        .map_or(*address, |ids| ids.primary_address(address))
+                };
+                let entry = acc.entry(key).or_default();
+                *entry += user_agg.clone();
+                entry.addresses.insert(*address);
             }
             acc
         })
 }
 
-///Aggregate, one value per (pool_type, address, midnight).
-pub async fn launch_agg(rconf: &RunConfig) -> Result<()> {
-    let mut writer = Writer::from_path("rows.csv")?;
+///Charges the gas of each transaction to the first pool it is seen in, pools being visited in the
+///same order every period: drops gas already charged by an earlier pool and marks the rest as
+///charged. Gas recorded without its transaction hash is kept as is.
+fn charge_gas_once(sevents: &mut SerializableEvents, charged_txs: &mut HashSet<H256>) {
+    let mut charge = |gas: &mut Option<GasCost>| {
+        if let Some(transaction_hash) = gas.and_then(|gas| gas.transaction_hash) {
+            if !charged_txs.insert(transaction_hash) {
+                *gas = None;
+            }
+        }
+    };
+    for debit in sevents
+        .longs
+        .values_mut()
+        .chain(sevents.shorts.values_mut())
+        .flatten()
+    {
+        charge(&mut debit.gas);
+    }
+    for debit in sevents.lps.values_mut().flatten() {
+        charge(&mut debit.gas);
+    }
+    sevents
+        .unattributed_gas
+        .retain(|transaction_hash, _| charged_txs.insert(*transaction_hash));
+}
+
+///Aggregate, one value per (pool group, address, midnight), pools being grouped at the
+///`aconf.group_by` level. Rows are sorted by (period, pool group, address) and hashed along with
+///the input events DBs into a manifest.
+pub async fn launch_agg(rconf: &RunConfig, aconf: &AggConfig) -> Result<Manifest> {
+    let inputs = hash_inputs(rconf, aconf)?;
+    let mut writer = Writer::from_path(aconf.out_dir.join(ROWS_FILENAME))?;
+    let mut pools_writer = Writer::from_path(aconf.out_dir.join(POOLS_FILENAME))?;
+    if let Some(identities) = aconf.identities.as_ref() {
+        let mut conflicts_writer =
+            Writer::from_path(aconf.out_dir.join(IDENTITY_CONFLICTS_FILENAME))?;
+        for (address, participants) in identities.conflicts.iter() {
+            conflicts_writer.serialize(IdentityConflictCsvRecord {
+                address: *address,
+                participants: participants.join(";"),
+            })?
+        }
+        conflicts_writer.flush()?;
+    }
+    let mut unregistered_writer = aconf
+        .participants
+        .as_ref()
+        .map(|_| Writer::from_path(aconf.out_dir.join(UNREGISTERED_FILENAME)))
+        .transpose()?;
+    let mut positions_writer = aconf
+        .positions_out
+        .as_ref()
+        .map(|filename| Writer::from_path(aconf.out_dir.join(filename)))
+        .transpose()?;
+    let mut rates_writer = aconf
+        .rates_out
+        .as_ref()
+        .map(|filename| Writer::from_path(aconf.out_dir.join(filename)))
+        .transpose()?;
+    let mut lots_writer = aconf
+        .lots_out
+        .as_ref()
+        .map(|filename| Writer::from_path(aconf.out_dir.join(filename)))
+        .transpose()?;
 
     let mut period_start = rconf
         .client
@@ -618,6 +2193,9 @@ pub async fn launch_agg(rconf: &RunConfig) -> Result<()> {
         "Aggregating"
     );
 
+    // Vault share prices per pool and block, read once across periods for the benchmark.
+    let mut vault_share_prices: HashMap<H160, BTreeMap<U64, U256>> = HashMap::new();
+
     while period_end <= end {
         let period_end_block_num = find_block_by_timestamp(
             rconf.client.clone(),
@@ -626,7 +2204,10 @@ pub async fn launch_agg(rconf: &RunConfig) -> Result<()> {
             rconf.end_block_num,
         )
         .await?;
-        let usersaggs_list_per_pooltype: DashMap<&str, Vec<UsersAggs>> = DashMap::new();
+        let usersaggs_list_per_group: DashMap<PoolGroupKey, Vec<UsersAggs>> = DashMap::new();
+        let mut group_base_is_eth: HashMap<PoolGroupKey, bool> = HashMap::new();
+        // A transaction touching several pools is charged its gas once.
+        let mut charged_txs: HashSet<H256> = HashSet::new();
 
         tracing::info!(
             period_start=?period_start,
@@ -637,69 +2218,337 @@ pub async fn launch_agg(rconf: &RunConfig) -> Result<()> {
             "AggPeriod"
         );
 
-        for hconf in HYPERDRIVES
+        let mut hconfs: Vec<&HyperdriveConfig> = HYPERDRIVES
             .values()
             .filter(|hc| hc.deploy_block_num < period_end_block_num)
-        {
-            let json_str =
-                fs::read_to_string(format!("{}-{}.json", hconf.pool_type, hconf.address))?;
+            .collect();
+        hconfs.sort_by_key(|hc| hc.address);
+
+        for hconf in hconfs {
+            let json_str = fs::read_to_string(eventsdb_filename(hconf))?;
             let events_db: EventsDb = serde_json::from_str(&json_str)?;
 
             let contract = i_hyperdrive::IHyperdrive::new(hconf.address, rconf.client.clone());
             let pool_config = contract.clone().get_pool_config().call().await?;
+            let base_decimals =
+                read_base_decimals(rconf.client.clone(), hconf, pool_config.base_token).await?;
             let tconf = SingleTrackerConfig {
                 hconf,
                 contract,
                 pool_config,
+                base_decimals,
             };
+            // Aggregation runs on 18 decimals amounts whatever the base token.
+            let decimal_overflows = DECIMAL_OVERFLOWS.load(Ordering::Relaxed);
+            let mut events = events_db.events.to_wad(base_decimals);
+            charge_gas_once(&mut events, &mut charged_txs);
+
+            let mut pool_aggs = get_hyperdrive_aggs(
+                rconf,
+                aconf,
+                &tconf,
+                &events,
+                period_start,
+                period_end,
+                vault_share_prices.entry(hconf.address).or_default(),
+            )
+            .await?;
+            if DECIMAL_OVERFLOWS.load(Ordering::Relaxed) > decimal_overflows {
+                pool_aggs.metrics.decimal_overflow = true;
+                for agg in pool_aggs.users_aggs.values_mut() {
+                    agg.decimal_overflow = true;
+                }
+            }
 
-            let users_aggs =
-                get_hyperdrive_aggs(rconf, &tconf, &events_db.events, period_start, period_end)
-                    .await?;
+            let metrics = &pool_aggs.metrics;
+            let decimal_overflows = DECIMAL_OVERFLOWS.load(Ordering::Relaxed);
+            let mut pool_record = PoolCsvRecord {
+                timestamp: timestamp_to_date_string(period_end),
+                block_number: period_end_block_num.as_u64(),
+                pool_type: hconf.pool_type.to_string(),
+                pool_address: hconf.address,
+                trade_count_longs: metrics.trade_count.long,
+                trade_count_shorts: metrics.trade_count.short,
+                trade_count_lps: metrics.trade_count.lp,
+                volume_longs: metrics.volume.long.normalized().compact_ser(),
+                volume_shorts: metrics.volume.short.normalized().compact_ser(),
+                volume_lps: metrics.volume.lp.normalized().compact_ser(),
+                net_base_flow_longs: metrics.net_base_flow.long.normalized().compact_ser(),
+                net_base_flow_shorts: metrics.net_base_flow.short.normalized().compact_ser(),
+                net_base_flow_lps: metrics.net_base_flow.lp.normalized().compact_ser(),
+                active_traders: metrics.active_traders,
+                longs_outstanding: metrics.longs_outstanding.normalized().compact_ser(),
+                shorts_outstanding: metrics.shorts_outstanding.normalized().compact_ser(),
+                share_reserves: metrics.share_reserves.normalized().compact_ser(),
+                lp_share_price: metrics.lp_share_price.normalized().compact_ser(),
+                vault_share_price: metrics.vault_share_price.normalized().compact_ser(),
+                spot_rate: metrics.spot_rate.compact_ser(),
+                fees_lps: metrics.fees_lps.compact_ser(),
+                fees_governance: metrics.fees_governance.compact_ser(),
+                lp_pnl_fees: metrics.lp_attribution.known().map(|a| a.fees.compact_ser()),
+                lp_pnl_idle_yield: metrics
+                    .lp_attribution
+                    .known()
+                    .map(|a| a.idle_yield.compact_ser()),
+                lp_pnl_counterparty: metrics
+                    .lp_attribution
+                    .known()
+                    .map(|a| a.counterparty.compact_ser()),
+                gas_unattributed: metrics.gas_unattributed.compact_ser(),
+                decimal_overflow: metrics.decimal_overflow,
+            };
+            pool_record.decimal_overflow |=
+                DECIMAL_OVERFLOWS.load(Ordering::Relaxed) > decimal_overflows;
+            pools_writer.serialize(pool_record)?;
+
+            if let Some(positions_writer) = positions_writer.as_mut() {
+                for ps in pool_aggs.positions.iter() {
+                    positions_writer.serialize(PositionCsvRecord {
+                        timestamp: timestamp_to_date_string(period_end),
+                        block_number: period_end_block_num.as_u64(),
+                        pool_type: hconf.pool_type.to_string(),
+                        pool_address: hconf.address,
+                        position_type: ps.position_type,
+                        user_address: ps.user_address,
+                        maturity_time: ps.maturity_time.map(timestamp_to_string),
+                        status: ps.status,
+                        amount: ps.amount.compact_ser(),
+                        cumulative_base_debit: ps.base_debit.compact_ser(),
+                        valuation: ps.valuation.compact_ser(),
+                        pnl: ps.pnl.compact_ser(),
+                        realized_pnl: ps.realized_pnl.compact_ser(),
+                        unrealized_pnl: ps.unrealized_pnl.compact_ser(),
+                        benchmark_pnl: ps.benchmark_pnl.compact_ser(),
+                        excess_pnl: (ps.pnl - ps.benchmark_pnl).compact_ser(),
+                    })?
+                }
+            }
 
-            usersaggs_list_per_pooltype
-                .entry(hconf.pool_type)
+            if let Some(rates_writer) = rates_writer.as_mut() {
+                for tr in pool_aggs.trade_rates.iter() {
+                    rates_writer.serialize(TradeRateCsvRecord {
+                        time: timestamp_to_string(tr.timestamp),
+                        block_number: tr.block_number.as_u64(),
+                        pool_type: hconf.pool_type.to_string(),
+                        pool_address: hconf.address,
+                        position_type: tr.position_type,
+                        user_address: tr.user_address,
+                        maturity_time: timestamp_to_string(tr.maturity_time),
+                        base_amount: tr.base_amount.compact_ser(),
+                        bond_amount: tr.bond_amount.compact_ser(),
+                        fixed_rate: tr.fixed_rate.compact_ser(),
+                    })?
+                }
+            }
+
+            if let Some(lots_writer) = lots_writer.as_mut() {
+                for plc in pool_aggs.lot_closes.iter() {
+                    lots_writer.serialize(LotCloseCsvRecord {
+                        timestamp: timestamp_to_date_string(period_end),
+                        pool_type: hconf.pool_type.to_string(),
+                        pool_address: hconf.address,
+                        position_type: plc.position_type,
+                        user_address: plc.user_address,
+                        maturity_time: plc.maturity_time.map(timestamp_to_string),
+                        lot_block_number: plc.close.lot_block_number.as_u64(),
+                        lot_time: timestamp_to_string(plc.close.lot_timestamp),
+                        close_block_number: plc.close.close_block_number.as_u64(),
+                        close_time: timestamp_to_string(plc.close.close_timestamp),
+                        amount: plc.close.amount.compact_ser(),
+                        cost: plc.close.cost.compact_ser(),
+                        proceeds: plc.close.proceeds.compact_ser(),
+                        pnl: plc.close.pnl.compact_ser(),
+                        holding_seconds: plc.close.holding_seconds(),
+                    })?
+                }
+            }
+
+            let users_aggs = pool_aggs.users_aggs;
+            let base_is_eth = tconf.pool_config.base_token == ETH_ADDRESS;
+            group_base_is_eth
+                .entry(aconf.group_by.group_key(&tconf))
+                .and_modify(|is_eth| *is_eth &= base_is_eth)
+                .or_insert(base_is_eth);
+            usersaggs_list_per_group
+                .entry(aconf.group_by.group_key(&tconf))
                 .and_modify(|existing| existing.push(users_aggs.clone()))
                 .or_insert_with(|| vec![users_aggs.clone()]);
         }
 
-        let pooltype_usersaggs: HashMap<&str, UsersAggs> = usersaggs_list_per_pooltype
+        let group_usersaggs: BTreeMap<PoolGroupKey, UsersAggs> = usersaggs_list_per_group
             .iter()
-            .map(|entry| (*entry.key(), group_users_aggs_by_address(entry.value())))
-            .collect::<HashMap<&str, UsersAggs>>();
+            .map(|entry| {
+                (
+                    *entry.key(),
+                    group_users_aggs_by_address(entry.value(), aconf),
+                )
+            })
+            .collect::<BTreeMap<PoolGroupKey, UsersAggs>>();
 
         tracing::debug!(
-            usersaggs_list_per_pooltype=?usersaggs_list_per_pooltype,
-            pooltype_usersaggs=?pooltype_usersaggs,
+            usersaggs_list_per_group=?usersaggs_list_per_group,
+            group_usersaggs=?group_usersaggs,
             "WritingAggsPerPeriod"
         );
 
-        for (pool_type, users_aggs) in pooltype_usersaggs.iter() {
+        for (group_key, users_aggs) in group_usersaggs.iter() {
             for (user_address, agg) in users_aggs {
-                writer.serialize(CsvRecord {
+                let decimal_overflows = DECIMAL_OVERFLOWS.load(Ordering::Relaxed);
+                // The excluded bucket is no one to follow up with, and its rows keep pools whole.
+                if let Some(participants) = aconf
+                    .participants
+                    .as_ref()
+                    .filter(|_| *user_address != EXCLUDED_ADDRESS)
+                {
+                    if !agg.addresses.iter().any(|a| participants.contains(a)) {
+                        let action_count =
+                            agg.action_count.long + agg.action_count.short + agg.action_count.lp;
+                        if let Some(unregistered_writer) =
+                            unregistered_writer.as_mut().filter(|_| action_count > 0)
+                        {
+                            let volume = agg.volume.long.normalized() - agg.churn_volume.long
+                                + agg.volume.short.normalized()
+                                - agg.churn_volume.short
+                                + agg.volume.lp.normalized()
+                                - agg.churn_volume.lp;
+                            unregistered_writer.serialize(UnregisteredCsvRecord {
+                                timestamp: timestamp_to_date_string(period_end),
+                                block_number: period_end_block_num.as_u64(),
+                                pool_type: group_key.pool_type.unwrap_or_default().to_string(),
+                                pool_address: group_key.pool_address,
+                                base_token: group_key.base_token,
+                                user_address: *user_address,
+                                member_addresses: agg.member_addresses(),
+                                action_count,
+                                volume: volume.compact_ser(),
+                            })?
+                        }
+                        continue;
+                    }
+                }
+
+                let total_pnl = agg.pnl.long + agg.pnl.short + agg.pnl.lp;
+                let returns_long = agg.capital.long.returns(agg.pnl.long, period_end);
+                let returns_short = agg.capital.short.returns(agg.pnl.short, period_end);
+                let returns_lp = agg.capital.lp.returns(agg.pnl.lp, period_end);
+                let returns = agg.capital.total().returns(total_pnl, period_end);
+                let roc_ser = |r: Option<(Decimal, Decimal)>| r.map(|(roc, _)| roc.compact_ser());
+                let apr_ser = |r: Option<(Decimal, Decimal)>| r.map(|(_, apr)| apr.compact_ser());
+                let usd_ser = |usd: Decimal| {
+                    (aconf.prices.is_some() && !agg.usd.missing_price).then(|| usd.compact_ser())
+                };
+
+                let mut record = CsvRecord {
                     timestamp: timestamp_to_date_string(period_end),
                     block_number: period_end_block_num.as_u64(),
-                    pool_type: pool_type.to_string(),
+                    pool_group: group_key.label(),
+                    pool_type: group_key.pool_type.unwrap_or_default().to_string(),
+                    pool_address: group_key.pool_address,
+                    base_token: group_key.base_token,
                     user_address: *user_address,
+                    excluded: *user_address == EXCLUDED_ADDRESS,
+                    participant: aconf
+                        .identities
+                        .as_ref()
+                        .and_then(|ids| ids.participant(user_address).map(String::from)),
+                    member_addresses: aconf.identities.as_ref().map(|_| agg.member_addresses()),
                     action_count_longs: agg.action_count.long,
                     action_count_shorts: agg.action_count.short,
                     action_count_lps: agg.action_count.lp,
-                    volume_longs: agg.volume.long.normalized().compact_ser(),
-                    volume_shorts: agg.volume.short.normalized().compact_ser(),
-                    volume_lps: agg.volume.lp.normalized().compact_ser(),
+                    volume_longs: (agg.volume.long.normalized() - agg.churn_volume.long)
+                        .compact_ser(),
+                    volume_shorts: (agg.volume.short.normalized() - agg.churn_volume.short)
+                        .compact_ser(),
+                    volume_lps: (agg.volume.lp.normalized() - agg.churn_volume.lp).compact_ser(),
+                    holding_median_seconds: median(&agg.holding_times),
+                    holding_max_seconds: agg.holding_times.iter().max().copied(),
                     pnl_longs: agg.pnl.long.compact_ser(),
                     pnl_shorts: agg.pnl.short.compact_ser(),
                     pnl_lps: agg.pnl.lp.compact_ser(),
                     tvl_longs: agg.base_cumulative_debit.long.normalized().compact_ser(),
                     tvl_shorts: agg.base_cumulative_debit.short.normalized().compact_ser(),
                     tvl_lps: agg.base_cumulative_debit.lp.normalized().compact_ser(),
-                })?
+                    twa_tvl_longs: agg.twa_base_debit.long.compact_ser(),
+                    twa_tvl_shorts: agg.twa_base_debit.short.compact_ser(),
+                    twa_tvl_lps: agg.twa_base_debit.lp.compact_ser(),
+                    twa_bonds_longs: agg.twa_amount.long.compact_ser(),
+                    twa_bonds_shorts: agg.twa_amount.short.compact_ser(),
+                    twa_lp_shares: agg.twa_amount.lp.compact_ser(),
+                    roc_longs: roc_ser(returns_long),
+                    roc_shorts: roc_ser(returns_short),
+                    roc_lps: roc_ser(returns_lp),
+                    roc: roc_ser(returns),
+                    apr_longs: apr_ser(returns_long),
+                    apr_shorts: apr_ser(returns_short),
+                    apr_lps: apr_ser(returns_lp),
+                    apr: apr_ser(returns),
+                    below_min_capital: aconf.min_capital.is_some_and(|min_capital| {
+                        agg.capital.total().time_weighted(period_end) < min_capital
+                    }),
+                    avg_fixed_rate_longs: agg.locked_rates.long.average().map(|r| r.compact_ser()),
+                    avg_fixed_rate_shorts: agg
+                        .locked_rates
+                        .short
+                        .average()
+                        .map(|r| r.compact_ser()),
+                    realized_pnl_longs: agg.realized_pnl.long.compact_ser(),
+                    realized_pnl_shorts: agg.realized_pnl.short.compact_ser(),
+                    realized_pnl_lps: agg.realized_pnl.lp.compact_ser(),
+                    unrealized_pnl_longs: agg.unrealized_pnl.long.compact_ser(),
+                    unrealized_pnl_shorts: agg.unrealized_pnl.short.compact_ser(),
+                    unrealized_pnl_lps: agg.unrealized_pnl.lp.compact_ser(),
+                    excess_pnl_longs: (agg.pnl.long - agg.benchmark_pnl.long).compact_ser(),
+                    excess_pnl_shorts: (agg.pnl.short - agg.benchmark_pnl.short).compact_ser(),
+                    excess_pnl_lps: (agg.pnl.lp - agg.benchmark_pnl.lp).compact_ser(),
+                    gas_spent: agg.gas_spent.compact_ser(),
+                    fees_paid: agg.fees_paid.compact_ser(),
+                    lp_pnl_fees: agg.lp_attribution.known().map(|a| a.fees.compact_ser()),
+                    lp_pnl_idle_yield: agg
+                        .lp_attribution
+                        .known()
+                        .map(|a| a.idle_yield.compact_ser()),
+                    lp_pnl_counterparty: agg
+                        .lp_attribution
+                        .known()
+                        .map(|a| a.counterparty.compact_ser()),
+                    volume_usd_longs: usd_ser(agg.usd.volume.long),
+                    volume_usd_shorts: usd_ser(agg.usd.volume.short),
+                    volume_usd_lps: usd_ser(agg.usd.volume.lp),
+                    pnl_usd_longs: usd_ser(agg.usd.pnl.long),
+                    pnl_usd_shorts: usd_ser(agg.usd.pnl.short),
+                    pnl_usd_lps: usd_ser(agg.usd.pnl.lp),
+                    tvl_usd_longs: usd_ser(agg.usd.tvl.long),
+                    tvl_usd_shorts: usd_ser(agg.usd.tvl.short),
+                    tvl_usd_lps: usd_ser(agg.usd.tvl.lp),
+                    pnl_net_of_gas: group_base_is_eth
+                        .get(group_key)
+                        .copied()
+                        .unwrap_or_default()
+                        .then(|| (total_pnl - agg.gas_spent).compact_ser()),
+                    decimal_overflow: agg.decimal_overflow,
+                };
+                record.decimal_overflow |=
+                    DECIMAL_OVERFLOWS.load(Ordering::Relaxed) > decimal_overflows;
+                writer.serialize(record)?
             }
         }
 
         tracing::info!("WritingAggs");
 
         writer.flush()?;
+        pools_writer.flush()?;
+        if let Some(unregistered_writer) = unregistered_writer.as_mut() {
+            unregistered_writer.flush()?;
+        }
+        if let Some(positions_writer) = positions_writer.as_mut() {
+            positions_writer.flush()?;
+        }
+        if let Some(rates_writer) = rates_writer.as_mut() {
+            rates_writer.flush()?;
+        }
+        if let Some(lots_writer) = lots_writer.as_mut() {
+            lots_writer.flush()?;
+        }
 
         period_start_datetime += Duration::days(1);
         period_start = U256::from(period_start_datetime.timestamp());
@@ -707,5 +2556,240 @@ pub async fn launch_agg(rconf: &RunConfig) -> Result<()> {
         period_end = U256::from(period_end_datetime.timestamp());
     }
 
+    let manifest = Manifest {
+        start_block_num: rconf.start_block_num.as_u64(),
+        end_block_num: rconf.end_block_num.as_u64(),
+        options: aconf.options.clone(),
+        inputs,
+        outputs: hash_outputs(&aconf.out_dir, &aconf.output_filenames())?,
+    };
+    write_manifest(&aconf.out_dir, &manifest)?;
+
+    tracing::info!(manifest=?manifest, "WroteManifest");
+
+    Ok(manifest)
+}
+
+///Re-runs the aggregation recorded in `manifest_dir`, with its recorded options, into a scratch
+///dir and fails if any input or output hash differs.
+pub async fn check_agg(rconf: &RunConfig, manifest_dir: &Path) -> Result<()> {
+    let expected = read_manifest(manifest_dir)?;
+
+    let rconf = RunConfig {
+        start_block_num: expected.start_block_num.into(),
+        end_block_num: expected.end_block_num.into(),
+        ..rconf.clone()
+    };
+    let aconf = AggConfig::from_options(
+        std::env::temp_dir().join(format!("hyperdrive-agg-check-{}", std::process::id())),
+        &expected.options,
+    )?;
+    fs::create_dir_all(&aconf.out_dir)?;
+
+    tracing::info!(rconf=?rconf, aconf=?aconf, "CheckingAgg");
+
+    let actual = launch_agg(&rconf, &aconf).await;
+    fs::remove_dir_all(&aconf.out_dir)?;
+    let diffs = diff_manifests(&expected, &actual?);
+
+    if !diffs.is_empty() {
+        for diff in diffs.iter() {
+            tracing::error!(diff=%diff, "ManifestMismatch");
+        }
+        bail!(
+            "Aggregation does not match manifest: {} difference(s)",
+            diffs.len()
+        );
+    }
+
+    tracing::info!("ManifestMatches");
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyperdrive_wrappers::wrappers::ihyperdrive::{PoolConfig, PoolInfo};
+
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    ///1000 idle vault shares at a share price of `vault_share_price`.
+    fn pool_state(vault_share_price: &str) -> hyperdrive_math::State {
+        let wad = |value: &str| U256::from_dec_str(value).unwrap() * U256::exp10(16);
+        let info = PoolInfo {
+            share_reserves: wad("100000"),
+            vault_share_price: wad(vault_share_price),
+            ..Default::default()
+        };
+        hyperdrive_math::State::new(PoolConfig::default(), info)
+    }
+
+    ///Users of a period as `(address, twa LP shares, cumulative LP PnL at the end)`.
+    fn users_aggs(users: &[(u64, &str, &str)]) -> UsersAggs {
+        users
+            .iter()
+            .map(|(address, twa_lp_shares, lp_pnl)| {
+                let mut agg = UserAgg::default();
+                agg.twa_amount.lp = dec(twa_lp_shares);
+                agg.pnl.lp = dec(lp_pnl);
+                (H160::from_low_u64_be(*address), agg)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lp_attribution_adds_up_to_period_pnl_change() {
+        // Two periods, share prices going 1 -> 1.01 -> 1.02, so 10 then 10 of idle yield.
+        let periods = [
+            (
+                "100",
+                "101",
+                dec("6"),
+                users_aggs(&[(1, "300", "20"), (2, "100", "10")]),
+            ),
+            (
+                "101",
+                "102",
+                dec("2"),
+                users_aggs(&[(1, "100", "35"), (2, "100", "12")]),
+            ),
+        ];
+
+        let mut start_lp_pnls: HashMap<H160, Decimal> = HashMap::new();
+        let mut first_user_counterparties = vec![];
+        for (start_price, end_price, fees_lps, mut users_aggs) in periods {
+            let metrics = PoolMetrics {
+                fees_lps,
+                vault_share_price: U256::from_dec_str(end_price).unwrap() * U256::exp10(16),
+                ..Default::default()
+            };
+            let total = attribute_lp_pnl(
+                &pool_state(start_price),
+                &metrics,
+                &start_lp_pnls,
+                &mut users_aggs,
+            );
+
+            let mut pnl_change_total = Decimal::ZERO;
+            for (address, agg) in users_aggs.iter() {
+                let attribution = agg.lp_attribution;
+                let pnl_change =
+                    agg.pnl.lp - start_lp_pnls.get(address).copied().unwrap_or_default();
+                assert_eq!(
+                    attribution.fees + attribution.idle_yield + attribution.counterparty,
+                    pnl_change
+                );
+                pnl_change_total += pnl_change;
+            }
+            assert_eq!(total.fees, fees_lps);
+            assert_eq!(total.idle_yield, dec("10"));
+            assert_eq!(
+                total.fees + total.idle_yield + total.counterparty,
+                pnl_change_total
+            );
+
+            first_user_counterparties.push(
+                users_aggs[&H160::from_low_u64_be(1)]
+                    .lp_attribution
+                    .counterparty,
+            );
+            start_lp_pnls = users_aggs
+                .iter()
+                .map(|(address, agg)| (*address, agg.pnl.lp))
+                .collect();
+        }
+
+        // User 1 holds 3/4 then 1/2 of the LP shares: 20 - 4.5 - 7.5, then 15 - 1 - 5.
+        assert_eq!(first_user_counterparties, vec![dec("8"), dec("9")]);
+    }
+
+    ///A pool with one long opened in each of `opens`, and the unattributed gas of `dropped`.
+    fn pool_events(opens: &[Option<u64>], dropped: &[u64]) -> SerializableEvents {
+        let gas = |transaction_hash: Option<u64>| GasCost {
+            gas_used: U256::from(21000),
+            effective_gas_price: U256::exp10(9),
+            transaction_hash: transaction_hash.map(H256::from_low_u64_be),
+        };
+        let longs = opens
+            .iter()
+            .enumerate()
+            .map(|(trader, transaction_hash)| {
+                let key = PositionKey {
+                    trader: H160::from_low_u64_be(trader as u64),
+                    maturity_time: U256::zero(),
+                };
+                let debit = PositionDebit {
+                    block_number: U64::zero(),
+                    timestamp: U256::zero(),
+                    base_amount: I256::zero(),
+                    bond_amount: I256::zero(),
+                    fixed_rate: None,
+                    gas: Some(gas(*transaction_hash)),
+                    fees: None,
+                };
+                (key, vec![debit])
+            })
+            .collect();
+        let unattributed_gas = dropped
+            .iter()
+            .map(|transaction_hash| {
+                let unattributed = UnattributedGas {
+                    block_number: U64::zero(),
+                    timestamp: U256::zero(),
+                    gas: gas(Some(*transaction_hash)),
+                };
+                (H256::from_low_u64_be(*transaction_hash), unattributed)
+            })
+            .collect();
+        SerializableEvents {
+            longs,
+            shorts: HashMap::new(),
+            lps: HashMap::new(),
+            share_prices: HashMap::new(),
+            router_longs: HashMap::new(),
+            router_shorts: HashMap::new(),
+            router_lps: HashMap::new(),
+            unattributed_gas,
+        }
+    }
+
+    fn charged_txs(sevents: &SerializableEvents) -> BTreeSet<Option<u64>> {
+        sevents
+            .longs
+            .values()
+            .flatten()
+            .filter_map(|debit| debit.gas)
+            .map(|gas| gas.transaction_hash.map(|hash| hash.to_low_u64_be()))
+            .chain(
+                sevents
+                    .unattributed_gas
+                    .keys()
+                    .map(|hash| Some(hash.to_low_u64_be())),
+            )
+            .collect()
+    }
+
+    #[test]
+    fn gas_is_charged_once_across_pools() {
+        let mut first = pool_events(&[Some(1), None], &[2]);
+        let mut second = pool_events(&[Some(1), Some(2), Some(3), None], &[1, 4]);
+
+        let mut charged = HashSet::new();
+        charge_gas_once(&mut first, &mut charged);
+        charge_gas_once(&mut second, &mut charged);
+
+        assert_eq!(
+            charged_txs(&first),
+            BTreeSet::from([None, Some(1), Some(2)])
+        );
+        // Gas recorded without its transaction hash can't be deduped and stays charged.
+        assert_eq!(
+            charged_txs(&second),
+            BTreeSet::from([None, Some(3), Some(4)])
+        );
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::sync::Arc;
+
+use csv::Writer;
+use ethers::{
+    providers::{Provider, Ws},
+    types::{H160, I256, U64},
+};
+use eyre::Result;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use hyperdrive_wrappers::wrappers::ihyperdrive::i_hyperdrive;
+
+use crate::globals::*;
+use crate::types::*;
+use crate::utils::*;
+
+pub const CHURN_FILENAME: &str = "churn.csv";
+
+const ROUND_TRIPS_SCORE: u32 = 40;
+const HEDGES_SCORE: u32 = 30;
+const LOCKSTEP_SCORE: u32 = 30;
+const FUNDED_SCORE: u32 = 20;
+
+///Suspicious patterns found for one address over every pool.
+#[derive(Debug, Clone, Default)]
+struct AddressChurn {
+    action_count: usize,
+    ///Closes following an open of the same position within the window.
+    round_trips: usize,
+    ///Long and short opens of the same maturity within the window, for about the same bonds.
+    hedges: usize,
+    ///Lowest address of the lockstep cluster, if any.
+    cluster: Option<H160>,
+    cluster_size: usize,
+    ///Common funder of the funded cluster, if any.
+    funder: Option<H160>,
+    funded_cluster_size: usize,
+}
+
+impl AddressChurn {
+    fn reasons(&self, cconf: &ChurnConfig) -> Vec<String> {
+        let mut reasons = Vec::new();
+        if self.round_trips >= cconf.min_repeats {
+            reasons.push(format!(
+                "{} round trips within {} blocks",
+                self.round_trips, cconf.window_blocks
+            ));
+        }
+        if self.hedges >= cconf.min_repeats {
+            reasons.push(format!(
+                "{} offsetting long/short opens within {} blocks",
+                self.hedges, cconf.window_blocks
+            ));
+        }
+        if let Some(cluster) = self.cluster {
+            reasons.push(format!(
+                "trades in lockstep with {} other wallets of cluster {:?}",
+                self.cluster_size - 1,
+                cluster
+            ));
+        }
+        if let Some(funder) = self.funder {
+            reasons.push(format!(
+                "funded by {:?} along with {} other trading wallets",
+                funder,
+                self.funded_cluster_size - 1
+            ));
+        }
+        reasons
+    }
+
+    fn risk_score(&self, cconf: &ChurnConfig) -> u32 {
+        let mut score = 0;
+        if self.round_trips >= cconf.min_repeats {
+            score += ROUND_TRIPS_SCORE;
+        }
+        if self.hedges >= cconf.min_repeats {
+            score += HEDGES_SCORE;
+        }
+        if self.cluster.is_some() {
+            score += LOCKSTEP_SCORE;
+        }
+        if self.funder.is_some() {
+            score += FUNDED_SCORE;
+        }
+        score
+    }
+}
+
+#[derive(Serialize)]
+struct ChurnCsvRecord {
+    user_address: H160,
+    action_count: usize,
+    round_trips: usize,
+    hedges: usize,
+    lockstep_cluster: Option<H160>,
+    lockstep_cluster_size: usize,
+    funded_cluster: Option<H160>,
+    funded_cluster_size: usize,
+    risk_score: u32,
+    is_bot: bool,
+    reasons: String,
+}
+
+///Counts closes each following the latest open of the same position within `window_blocks`.
+fn count_round_trips<I>(debits: I, window_blocks: u64) -> usize
+where
+    I: IntoIterator<Item = (U64, I256)>,
+{
+    let mut last_open: Option<U64> = None;
+    let mut round_trips = 0;
+    for (block_number, amount) in debits {
+        if amount >= I256::zero() {
+            last_open = Some(block_number);
+        } else if let Some(open_block_number) = last_open {
+            if (block_number - open_block_number).as_u64() <= window_blocks {
+                round_trips += 1;
+            }
+        }
+    }
+    round_trips
+}
+
+///Counts long opens offset by a short open of the same maturity within `window_blocks`.
+fn count_hedges(cconf: &ChurnConfig, long: &[PositionDebit], short: &[PositionDebit]) -> usize {
+    let opens = |debits: &[PositionDebit]| -> Vec<(U64, Decimal)> {
+        debits
+            .iter()
+            .filter(|debit| debit.bond_amount > I256::zero())
+            .map(|debit| (debit.block_number, debit.bond_amount.normalized()))
+            .collect()
+    };
+    let short_opens = opens(short);
+    opens(long)
+        .iter()
+        .filter(|(long_block_number, long_bonds)| {
+            short_opens.iter().any(|(short_block_number, short_bonds)| {
+                let blocks_apart = if long_block_number > short_block_number {
+                    *long_block_number - *short_block_number
+                } else {
+                    *short_block_number - *long_block_number
+                };
+                blocks_apart.as_u64() <= cconf.window_blocks
+                    && (*long_bonds - *short_bonds).abs()
+                        <= cconf.exposure_tolerance * Decimal::max(*long_bonds, *short_bonds)
+            })
+        })
+        .count()
+}
+
+fn find_root(parents: &mut BTreeMap<H160, H160>, address: H160) -> H160 {
+    let parent = *parents.entry(address).or_insert(address);
+    if parent == address {
+        return address;
+    }
+    let root = find_root(parents, parent);
+    parents.insert(address, root);
+    root
+}
+
+///Groups wallets that both trade in at least `lockstep_blocks` same blocks, transitively. Keyed
+///by member, valued by the cluster lowest address.
+fn find_lockstep_clusters(
+    cconf: &ChurnConfig,
+    traders_per_block: &BTreeMap<U64, BTreeSet<H160>>,
+) -> BTreeMap<H160, H160> {
+    let mut shared_blocks: BTreeMap<(H160, H160), usize> = BTreeMap::new();
+    for traders in traders_per_block.values() {
+        for (idx, first) in traders.iter().enumerate() {
+            for second in traders.iter().skip(idx + 1) {
+                *shared_blocks.entry((*first, *second)).or_default() += 1;
+            }
+        }
+    }
+
+    let mut parents: BTreeMap<H160, H160> = BTreeMap::new();
+    for ((first, second), count) in shared_blocks {
+        if count >= cconf.lockstep_blocks {
+            let first_root = find_root(&mut parents, first);
+            let second_root = find_root(&mut parents, second);
+            // Lowest address as root, so that cluster ids are deterministic.
+            let (root, child) = if first_root < second_root {
+                (first_root, second_root)
+            } else {
+                (second_root, first_root)
+            };
+            parents.insert(child, root);
+        }
+    }
+
+    let members: Vec<H160> = parents.keys().copied().collect();
+    members
+        .into_iter()
+        .map(|address| (address, find_root(&mut parents, address)))
+        .collect()
+}
+
+///Groups trading wallets by funder, keeping funders of at least `min_funded` of them. Keyed by
+///member, valued by the funder.
+fn find_funded_clusters(
+    cconf: &ChurnConfig,
+    funders: &BTreeMap<H160, H160>,
+    traders: impl Iterator<Item = H160>,
+) -> BTreeMap<H160, H160> {
+    let mut funded: BTreeMap<H160, Vec<H160>> = BTreeMap::new();
+    for trader in traders {
+        if let Some(funder) = funders.get(&trader) {
+            funded.entry(*funder).or_default().push(trader);
+        }
+    }
+    funded
+        .into_iter()
+        .filter(|(_, members)| members.len() >= cconf.min_funded)
+        .flat_map(|(funder, members)| members.into_iter().map(move |member| (member, funder)))
+        .collect()
+}
+
+///Scores every address of the events DBs for round trips, self-offsetting opens, lockstep trading
+///and, given funders, common funding, the reasons being listed alongside.
+pub async fn launch_churn(client: Arc<Provider<Ws>>, cconf: &ChurnConfig) -> Result<()> {
+    let mut churns: BTreeMap<H160, AddressChurn> = BTreeMap::new();
+    let mut traders_per_block: BTreeMap<U64, BTreeSet<H160>> = BTreeMap::new();
+
+    let mut hconfs: Vec<&HyperdriveConfig> = HYPERDRIVES.values().collect();
+    hconfs.sort_by_key(|hc| hc.address);
+
+    for hconf in hconfs {
+        let json_str = match fs::read_to_string(eventsdb_filename(hconf)) {
+            Ok(json_str) => json_str,
+            Err(_) => {
+                tracing::warn!(pool_type = hconf.pool_type, address=?hconf.address, "MissingEventsDb");
+                continue;
+            }
+        };
+        let events_db: EventsDb = serde_json::from_str(&json_str)?;
+        let contract = i_hyperdrive::IHyperdrive::new(hconf.address, client.clone());
+        let pool_config = contract.get_pool_config().call().await?;
+        let base_decimals =
+            read_base_decimals(client.clone(), hconf, pool_config.base_token).await?;
+        // Hedges compare normalized bond amounts, which assumes 18 decimals.
+        let events = events_db.events.to_wad(base_decimals);
+
+        tracing::info!(pool_type = hconf.pool_type, address=?hconf.address, "AnalyzingChurn");
+
+        for (key, debits) in events.longs.iter().chain(events.shorts.iter()) {
+            let churn = churns.entry(key.trader).or_default();
+            churn.action_count += debits.len();
+            churn.round_trips += count_round_trips(
+                debits.iter().map(|d| (d.block_number, d.bond_amount)),
+                cconf.window_blocks,
+            );
+            for debit in debits {
+                traders_per_block
+                    .entry(debit.block_number)
+                    .or_default()
+                    .insert(key.trader);
+            }
+        }
+        for (key, debits) in events.lps.iter() {
+            let churn = churns.entry(key.provider).or_default();
+            churn.action_count += debits.len();
+            churn.round_trips += count_round_trips(
+                debits.iter().map(|d| (d.block_number, d.lp_amount)),
+                cconf.window_blocks,
+            );
+            for debit in debits {
+                traders_per_block
+                    .entry(debit.block_number)
+                    .or_default()
+                    .insert(key.provider);
+            }
+        }
+        for (key, long) in events.longs.iter() {
+            if let Some(short) = events.shorts.get(key) {
+                churns.entry(key.trader).or_default().hedges += count_hedges(cconf, long, short);
+            }
+        }
+    }
+
+    let clusters = find_lockstep_clusters(cconf, &traders_per_block);
+    let mut cluster_sizes: BTreeMap<H160, usize> = BTreeMap::new();
+    for root in clusters.values() {
+        *cluster_sizes.entry(*root).or_default() += 1;
+    }
+    for (address, root) in clusters.iter() {
+        let churn = churns.entry(*address).or_default();
+        churn.cluster = Some(*root);
+        churn.cluster_size = cluster_sizes[root];
+    }
+
+    if let Some(funders) = cconf.funders.as_ref() {
+        let funded_clusters = find_funded_clusters(cconf, funders, churns.keys().copied());
+        let mut funded_sizes: BTreeMap<H160, usize> = BTreeMap::new();
+        for funder in funded_clusters.values() {
+            *funded_sizes.entry(*funder).or_default() += 1;
+        }
+        for (address, funder) in funded_clusters.iter() {
+            let churn = churns.entry(*address).or_default();
+            churn.funder = Some(*funder);
+            churn.funded_cluster_size = funded_sizes[funder];
+        }
+    }
+
+    let mut writer = Writer::from_path(cconf.out_dir.join(CHURN_FILENAME))?;
+    for (address, churn) in churns.iter() {
+        let risk_score = churn.risk_score(cconf);
+        writer.serialize(ChurnCsvRecord {
+            user_address: *address,
+            action_count: churn.action_count,
+            round_trips: churn.round_trips,
+            hedges: churn.hedges,
+            lockstep_cluster: churn.cluster,
+            lockstep_cluster_size: churn.cluster_size,
+            funded_cluster: churn.funder,
+            funded_cluster_size: churn.funded_cluster_size,
+            risk_score,
+            is_bot: risk_score >= cconf.bot_score,
+            reasons: churn.reasons(cconf).join("; "),
+        })?
+    }
+    writer.flush()?;
+
+    tracing::info!(
+        addresses = churns.len(),
+        clustered = clusters.len(),
+        "ChurnReported"
+    );
+
+    Ok(())
+}
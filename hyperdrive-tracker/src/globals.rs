@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::sync::atomic::AtomicUsize;
 
 use ethers::types::{H160, U64};
 use hex_literal::hex;
@@ -8,6 +9,13 @@ use crate::types::*;
 pub const DECIMAL_SCALE: u32 = 18;
 pub const DECIMAL_PRECISION: u32 = 8;
 pub const QUERY_PAGE_SIZE: u64 = 100u64;
+pub const SECONDS_PER_YEAR: u64 = 365 * 24 * 60 * 60;
+///Base token of pools denominated in ETH.
+pub const ETH_ADDRESS: H160 = H160(hex!("eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee"));
+///Selector of the ERC20 `decimals()` view.
+pub const ERC20_DECIMALS_SELECTOR: [u8; 4] = hex!("313ce567");
+///Conversions to `Decimal` that overflowed so far, see `Decimalizable`.
+pub static DECIMAL_OVERFLOWS: AtomicUsize = AtomicUsize::new(0);
 
 lazy_static! {
     pub static ref HYPERDRIVES: HashMap<&'static str, HyperdriveConfig> = [
@@ -16,7 +24,8 @@ lazy_static! {
             HyperdriveConfig {
                 pool_type: "4626",
                 address: H160(hex!("392839da0dacac790bd825c81ce2c5e264d793a8")),
-                deploy_block_num: U64::from(5664183)
+                deploy_block_num: U64::from(5664183),
+                base_decimals: None
             }
         ),
         (
@@ -24,7 +33,8 @@ lazy_static! {
             HyperdriveConfig {
                 pool_type: "stETH",
                 address: H160(hex!("ff33bd6d7ed4119c99c310f3e5f0fa467796ee23")),
-                deploy_block_num: U64::from(5663018)
+                deploy_block_num: U64::from(5663018),
+                base_decimals: None
             }
         ),
         (
@@ -32,7 +42,8 @@ lazy_static! {
             HyperdriveConfig {
                 pool_type: "4626",
                 address: H160(hex!("0436b07823da988484b70309b0d1b509eadd2173")),
-                deploy_block_num: U64::from(5755457)
+                deploy_block_num: U64::from(5755457),
+                base_decimals: None
             }
         ),
         (
@@ -40,7 +51,8 @@ lazy_static! {
             HyperdriveConfig {
                 pool_type: "stETH",
                 address: H160(hex!("72e19347512c194a6812c72934bf0439ffb31a26")),
-                deploy_block_num: U64::from(5768223)
+                deploy_block_num: U64::from(5768223),
+                base_decimals: None
             }
         ),
         (
@@ -48,7 +60,8 @@ lazy_static! {
             HyperdriveConfig {
                 pool_type: "stETH",
                 address: H160(hex!("4e38fd41c03ff11b3426efae53138b86116797b8")),
-                deploy_block_num: U64::from(5663061)
+                deploy_block_num: U64::from(5663061),
+                base_decimals: None
             }
         ),
         (
@@ -56,7 +69,8 @@ lazy_static! {
             HyperdriveConfig {
                 pool_type: "4626",
                 address: H160(hex!("b932f8085399c228b16a9f7fc3219d47ffa2810d")),
-                deploy_block_num: U64::from(5664214)
+                deploy_block_num: U64::from(5664214),
+                base_decimals: None
             }
         ),
     ]
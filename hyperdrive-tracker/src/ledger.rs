@@ -0,0 +1,303 @@
+use std::collections::VecDeque;
+
+use ethers::types::{I256, U256, U64};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::globals::*;
+use crate::types::*;
+use crate::utils::*;
+
+///A position or LP debit as seen by the ledger. `amount` is bonds or LP shares.
+#[derive(Debug, Clone, Copy)]
+pub struct LedgerDebit {
+    pub block_number: U64,
+    pub timestamp: U256,
+    pub base_amount: I256,
+    pub amount: I256,
+}
+
+impl From<&PositionDebit> for LedgerDebit {
+    fn from(debit: &PositionDebit) -> Self {
+        LedgerDebit {
+            block_number: debit.block_number,
+            timestamp: debit.timestamp,
+            base_amount: debit.base_amount,
+            amount: debit.bond_amount,
+        }
+    }
+}
+
+impl From<&LpDebit> for LedgerDebit {
+    fn from(debit: &LpDebit) -> Self {
+        LedgerDebit {
+            block_number: debit.block_number,
+            timestamp: debit.timestamp,
+            base_amount: debit.base_amount,
+            amount: debit.lp_amount,
+        }
+    }
+}
+
+///What remains held of one opening debit, with the base cost backing it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Lot {
+    pub block_number: U64,
+    pub timestamp: U256,
+    pub amount: Decimal,
+    pub cost: Decimal,
+}
+
+///Part of a close matched against one lot.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct LotClose {
+    pub lot_block_number: U64,
+    pub lot_timestamp: U256,
+    pub close_block_number: U64,
+    pub close_timestamp: U256,
+    pub amount: Decimal,
+    pub cost: Decimal,
+    pub proceeds: Decimal,
+    pub pnl: Decimal,
+}
+
+impl LotClose {
+    pub fn holding_seconds(&self) -> u64 {
+        (self.close_timestamp - self.lot_timestamp).as_u64()
+    }
+}
+
+///Open lots and realized closes of one position, in debit order.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LotLedger {
+    pub lots: VecDeque<Lot>,
+    pub closes: Vec<LotClose>,
+}
+
+impl LotLedger {
+    ///Replays debits, opens being positive and closes negative.
+    pub fn from_debits<I>(debits: I, cost_basis: CostBasis) -> Self
+    where
+        I: IntoIterator<Item = LedgerDebit>,
+    {
+        let mut ledger = LotLedger::default();
+        for debit in debits {
+            if debit.amount >= I256::zero() {
+                ledger.open(&debit);
+            } else {
+                ledger.close(&debit, cost_basis);
+            }
+        }
+        ledger
+    }
+
+    fn open(&mut self, debit: &LedgerDebit) {
+        self.lots.push_back(Lot {
+            block_number: debit.block_number,
+            timestamp: debit.timestamp,
+            amount: debit.amount.normalized(),
+            cost: debit.base_amount.normalized(),
+        });
+    }
+
+    ///FIFO consumes the oldest lots first. Average cost takes from every lot pro rata, so each
+    ///close releases `cost_basis * closed / held` as with a single pooled lot. Any part of the
+    ///close beyond the held lots, and closes too small to normalize to a non-zero amount, are
+    ///realized at zero cost, so that realized PnL still accounts for all proceeds.
+    fn close(&mut self, debit: &LedgerDebit, cost_basis: CostBasis) {
+        let close_amount = -debit.amount.normalized();
+        let proceeds = -debit.base_amount.normalized();
+        if close_amount.is_zero() {
+            if !proceeds.is_zero() {
+                self.closes.push(LotClose {
+                    lot_block_number: debit.block_number,
+                    lot_timestamp: debit.timestamp,
+                    close_block_number: debit.block_number,
+                    close_timestamp: debit.timestamp,
+                    amount: Decimal::ZERO,
+                    cost: Decimal::ZERO,
+                    proceeds,
+                    pnl: proceeds,
+                });
+            }
+            return;
+        }
+        let held_amount = self.held_amount();
+        let matched_amount = Decimal::min(close_amount, held_amount);
+
+        let mut matched: Vec<(usize, Decimal)> = Vec::new();
+        match cost_basis {
+            CostBasis::Fifo => {
+                let mut remaining = matched_amount;
+                for (idx, lot) in self.lots.iter().enumerate() {
+                    if remaining <= Decimal::ZERO {
+                        break;
+                    }
+                    let taken = Decimal::min(lot.amount, remaining);
+                    matched.push((idx, taken));
+                    remaining -= taken;
+                }
+            }
+            CostBasis::AverageCost => {
+                for (idx, lot) in self.lots.iter().enumerate() {
+                    matched.push((idx, lot.amount * matched_amount / held_amount));
+                }
+            }
+        }
+
+        for (idx, taken) in matched.into_iter().filter(|(_, taken)| !taken.is_zero()) {
+            let lot = &mut self.lots[idx];
+            let cost = if lot.amount.is_zero() {
+                Decimal::ZERO
+            } else {
+                lot.cost * taken / lot.amount
+            };
+            let lot_proceeds = proceeds * taken / close_amount;
+            lot.amount -= taken;
+            lot.cost -= cost;
+            self.closes.push(LotClose {
+                lot_block_number: lot.block_number,
+                lot_timestamp: lot.timestamp,
+                close_block_number: debit.block_number,
+                close_timestamp: debit.timestamp,
+                amount: taken.round_dp(DECIMAL_PRECISION),
+                cost: cost.round_dp(DECIMAL_PRECISION),
+                proceeds: lot_proceeds.round_dp(DECIMAL_PRECISION),
+                pnl: (lot_proceeds - cost).round_dp(DECIMAL_PRECISION),
+            });
+        }
+
+        let unmatched_amount = close_amount - matched_amount;
+        if unmatched_amount > Decimal::ZERO {
+            tracing::warn!(
+                debit=?debit,
+                held_amount=%held_amount,
+                unmatched_amount=%unmatched_amount,
+                "CloseExceedsOpenLots"
+            );
+            let unmatched_proceeds = proceeds * unmatched_amount / close_amount;
+            self.closes.push(LotClose {
+                lot_block_number: debit.block_number,
+                lot_timestamp: debit.timestamp,
+                close_block_number: debit.block_number,
+                close_timestamp: debit.timestamp,
+                amount: unmatched_amount.round_dp(DECIMAL_PRECISION),
+                cost: Decimal::ZERO,
+                proceeds: unmatched_proceeds.round_dp(DECIMAL_PRECISION),
+                pnl: unmatched_proceeds.round_dp(DECIMAL_PRECISION),
+            });
+        }
+
+        self.lots.retain(|lot| lot.amount > Decimal::ZERO);
+    }
+
+    pub fn held_amount(&self) -> Decimal {
+        self.lots.iter().map(|lot| lot.amount).sum()
+    }
+
+    pub fn realized_pnl(&self) -> Decimal {
+        self.closes.iter().map(|close| close.pnl).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn debit(block_number: u64, amount: i64, base_amount: i64) -> LedgerDebit {
+        LedgerDebit {
+            block_number: U64::from(block_number),
+            timestamp: U256::from(block_number * 12),
+            base_amount: I256::from(base_amount) * I256::exp10(DECIMAL_SCALE as usize),
+            amount: I256::from(amount) * I256::exp10(DECIMAL_SCALE as usize),
+        }
+    }
+
+    fn dec(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    ///Two lots, 100 bonds for 90 base then 100 bonds for 95 base.
+    fn two_lots(cost_basis: CostBasis) -> LotLedger {
+        LotLedger::from_debits([debit(1, 100, 90), debit(2, 100, 95)], cost_basis)
+    }
+
+    fn close(ledger: &mut LotLedger, block_number: u64, amount: i64, proceeds: i64) {
+        ledger.close(&debit(block_number, -amount, -proceeds), CostBasis::Fifo);
+    }
+
+    #[test]
+    fn fifo_closes() {
+        let mut ledger = two_lots(CostBasis::Fifo);
+
+        // Partial close of the first lot: 48 - 90 * 50 / 100.
+        close(&mut ledger, 3, 50, 48);
+        assert_eq!(ledger.realized_pnl(), dec("3"));
+        assert_eq!(ledger.held_amount(), dec("150"));
+
+        // Spanning both lots: 49 - 45 on the first, 49 - 47.5 on the second.
+        close(&mut ledger, 4, 100, 98);
+        assert_eq!(ledger.closes.len(), 3);
+        assert_eq!(ledger.closes[1].pnl, dec("4"));
+        assert_eq!(ledger.closes[2].pnl, dec("1.5"));
+        assert_eq!(ledger.realized_pnl(), dec("8.5"));
+        assert_eq!(ledger.held_amount(), dec("50"));
+
+        // Full close of what remains: 50 - 47.5.
+        close(&mut ledger, 5, 50, 50);
+        assert_eq!(ledger.realized_pnl(), dec("11"));
+        assert!(ledger.lots.is_empty());
+    }
+
+    #[test]
+    fn average_cost_closes() {
+        let mut ledger = two_lots(CostBasis::AverageCost);
+
+        // Partial close at the pooled cost: 48 - 185 * 50 / 200.
+        ledger.close(&debit(3, -50, -48), CostBasis::AverageCost);
+        assert_eq!(ledger.closes.len(), 2);
+        assert_eq!(ledger.closes[0].cost, dec("22.5"));
+        assert_eq!(ledger.closes[1].cost, dec("23.75"));
+        assert_eq!(ledger.realized_pnl(), dec("1.75"));
+        assert_eq!(ledger.held_amount(), dec("150"));
+
+        // Spanning both lots: 98 - 138.75 * 100 / 150.
+        ledger.close(&debit(4, -100, -98), CostBasis::AverageCost);
+        assert_eq!(ledger.realized_pnl(), dec("7.25"));
+        assert_eq!(ledger.held_amount(), dec("50"));
+
+        // Full close of what remains: 50 - 46.25.
+        ledger.close(&debit(5, -50, -50), CostBasis::AverageCost);
+        assert_eq!(ledger.realized_pnl(), dec("11"));
+        assert!(ledger.lots.is_empty());
+    }
+
+    #[test]
+    fn over_close_realizes_excess_at_zero_cost() {
+        for cost_basis in [CostBasis::Fifo, CostBasis::AverageCost] {
+            let mut ledger = LotLedger::from_debits([debit(1, 100, 90)], cost_basis);
+            // 100 - 90 on the lot, then 50 at zero cost.
+            ledger.close(&debit(2, -150, -150), cost_basis);
+            assert_eq!(ledger.closes.len(), 2);
+            assert_eq!(ledger.closes[0].pnl, dec("10"));
+            assert_eq!(ledger.closes[1].cost, Decimal::ZERO);
+            assert_eq!(ledger.closes[1].pnl, dec("50"));
+            assert_eq!(ledger.realized_pnl(), dec("60"));
+            assert!(ledger.lots.is_empty());
+        }
+    }
+
+    #[test]
+    fn dust_close_keeps_lots() {
+        for cost_basis in [CostBasis::Fifo, CostBasis::AverageCost] {
+            let mut ledger = two_lots(cost_basis);
+            let dust = LedgerDebit {
+                amount: -I256::one(),
+                ..debit(3, 0, -1)
+            };
+            ledger.close(&dust, cost_basis);
+            assert_eq!(ledger.realized_pnl(), dec("1"));
+            assert_eq!(ledger.held_amount(), dec("200"));
+        }
+    }
+}
@@ -1,4 +1,6 @@
+use std::collections::BTreeMap;
 use std::env;
+use std::path::{Path, PathBuf};
 //use std::str::FromStr;
 use std::sync::Arc;
 
@@ -7,9 +9,10 @@ use clap::{arg, command, Command};
 use dotenv::dotenv;
 use ethers::{
     providers::{Middleware, Provider, Ws},
-    types::BlockNumber,
+    types::{BlockNumber, U64},
 };
 use eyre::{bail, Result};
+use rust_decimal::Decimal;
 
 use hyperdrive_wrappers::wrappers::ihyperdrive::i_hyperdrive;
 
@@ -17,13 +20,22 @@ use hyperdrive_wrappers::wrappers::ihyperdrive::i_hyperdrive;
 extern crate lazy_static;
 use crate::acq::*;
 use crate::agg::*;
+use crate::churn::*;
 use crate::globals::*;
+use crate::manifest::*;
+use crate::maturities::*;
+use crate::reconcile::*;
 use crate::types::*;
 use crate::utils::*;
 
 mod acq;
 mod agg;
+mod churn;
 mod globals;
+mod ledger;
+mod manifest;
+mod maturities;
+mod reconcile;
 mod types;
 mod utils;
 
@@ -45,11 +57,54 @@ async fn main() -> Result<()> {
         .subcommand(
             Command::new("acq")
                 .arg(arg!([hyperdrive_id] "The 0x1234 of the Hyperdrive instance").required(true))
-                .arg(arg!(-p --page_size <PAGE_SIZE> "Query page size")),
+                .arg(arg!(-p --page_size <PAGE_SIZE> "Query page size"))
+                .arg(arg!(--routers <LIST> "Router contracts to attribute, file or comma-separated"))
+                .arg(arg!(--attribution <ATTRIBUTION> "Router events credited to: tx_from, destination"))
+                .arg(arg!(--receipts "Fetch transaction receipts to record gas costs"))
+                .arg(arg!(--fees "Estimate the fees paid on every trade")),
         )
         .subcommand(
             Command::new("agg")
-                .arg(arg!(-e --end_date <END_DATE> "Custom end date like `%YYYY-%mm-%dd`")),
+                .arg(arg!(-e --end_date <END_DATE> "Custom end date like `%YYYY-%mm-%dd`"))
+                .arg(arg!(-c --check "Re-run the manifest aggregation with its options and verify hashes"))
+                .arg(arg!(-g --group_by <GROUP_BY> "Pools grouping: address, pool_type, base_token, all"))
+                .arg(arg!(-v --valuation <VALUATION> "Open positions valuation: maturity, market"))
+                .arg(arg!(-b --cost_basis <COST_BASIS> "Realized PnL method: fifo, average_cost"))
+                .arg(
+                    arg!(--positions_out <POSITIONS_OUT> "Per-position statements CSV file name")
+                        .visible_alias("positions-out"),
+                )
+                .arg(arg!(--rates_out <RATES_OUT> "Opening trades fixed rates CSV file name"))
+                .arg(arg!(--lots_out <LOTS_OUT> "Per-lot realized closes CSV file name"))
+                .arg(arg!(--min_capital <MIN_CAPITAL> "Flag users with less time-weighted capital"))
+                .arg(arg!(--min_holding <SECONDS> "Reduce volume of lots held for less than this"))
+                .arg(arg!(--holding_weighting <HOLDING_WEIGHTING> "Short-held lots volume: exclude, pro_rata"))
+                .arg(arg!(--identities <FILE> "address,participant CSV of wallets to merge"))
+                .arg(arg!(--allow <LIST> "Only aggregate these addresses, file or comma-separated"))
+                .arg(arg!(--deny <LIST> "Aggregate these addresses apart, file or comma-separated"))
+                .arg(arg!(--participants <FILE> "Registered addresses CSV or JSON, to restrict rows to"))
+                .arg(arg!(--prices <FILE> "timestamp,base_token,price CSV or JSON of USD prices")),
+        )
+        .subcommand(
+            Command::new("churn")
+                .arg(arg!(-w --window_blocks <BLOCKS> "Blocks within which trades are a round trip"))
+                .arg(arg!(-r --min_repeats <COUNT> "Round trips or hedges from which to flag"))
+                .arg(arg!(--lockstep_blocks <COUNT> "Shared blocks from which wallets are in lockstep"))
+                .arg(arg!(--exposure_tolerance <RATIO> "Relative bonds difference of offsetting opens"))
+                .arg(arg!(--bot_score <SCORE> "Risk score from which an address is a bot"))
+                .arg(arg!(--funding <FILE> "address,funder CSV to cluster wallets by funding source"))
+                .arg(arg!(--min_funded <COUNT> "Trading wallets of a funder from which they are a cluster")),
+        )
+        .subcommand(
+            Command::new("maturities")
+                .arg(arg!(-d --date <DATE> "Custom date like `%YYYY-%mm-%dd`, events DBs end otherwise"))
+                .arg(arg!(-n --days <DAYS> "List holders of positions maturing within this many days")),
+        )
+        .subcommand(
+            Command::new("reconcile")
+                .arg(arg!(-b --block <BLOCK> "Block number to reconcile at, events DBs end otherwise"))
+                .arg(arg!(-t --tolerance <RATIO> "Relative difference from which amounts mismatch"))
+                .arg(arg!(-s --samples <COUNT> "Largest holdings per position type to check")),
         )
         .get_matches();
 
@@ -65,11 +120,14 @@ async fn main() -> Result<()> {
 
             let contract = i_hyperdrive::IHyperdrive::new(hconf.address, client.clone());
             let pool_config = contract.clone().get_pool_config().call().await?;
+            let base_decimals =
+                read_base_decimals(client.clone(), hconf, pool_config.base_token).await?;
 
             let tconf = SingleTrackerConfig {
                 hconf,
                 contract,
                 pool_config,
+                base_decimals,
             };
             let mut rconf = RunConfig {
                 client: client.clone(),
@@ -83,9 +141,21 @@ async fn main() -> Result<()> {
                 rconf.page_size = page_size.into();
             }
 
-            tracing::info!(tconf=?tconf, rconf=?rconf, "LaunchingAcq");
+            let mut qconf = AcqConfig {
+                fetch_receipts: sub_matches.get_flag("receipts"),
+                estimate_fees: sub_matches.get_flag("fees"),
+                ..Default::default()
+            };
+            if let Some(routers_str) = sub_matches.get_one::<String>("routers") {
+                qconf.routers = read_address_patterns(routers_str)?;
+            }
+            if let Some(attr_str) = sub_matches.get_one::<String>("attribution") {
+                qconf.attribution = attr_str.parse()?;
+            }
+
+            tracing::info!(tconf=?tconf, rconf=?rconf, qconf=?qconf, "LaunchingAcq");
 
-            launch_acq(&rconf, &tconf).await
+            launch_acq(&rconf, &tconf, &qconf).await
         }
         Some(("agg", sub_matches)) => {
             let earliest_deploy_block_num = HYPERDRIVES
@@ -100,6 +170,21 @@ async fn main() -> Result<()> {
                 end_block_num: latest_block_num,
             };
 
+            let options: BTreeMap<String, String> = AGG_OPTIONS
+                .iter()
+                .filter_map(|name| {
+                    sub_matches
+                        .get_one::<String>(name)
+                        .map(|value| (name.to_string(), value.clone()))
+                })
+                .collect();
+
+            if sub_matches.get_flag("check") {
+                tracing::info!(manifest = MANIFEST_FILENAME, "LaunchingAggCheck");
+
+                return check_agg(&rconf, Path::new(".")).await;
+            }
+
             if let Some(ed_str) = sub_matches.get_one::<String>("end_date") {
                 let datetime = NaiveDate::parse_from_str(ed_str, "%Y-%m-%d")?
                     .and_hms_opt(0, 0, 0)
@@ -116,9 +201,127 @@ async fn main() -> Result<()> {
                 rconf.end_block_num = block_num;
             }
 
-            tracing::info!(rconf=?rconf, "LaunchingAgg");
+            let aconf = AggConfig::from_options(PathBuf::from("."), &options)?;
+
+            tracing::info!(rconf=?rconf, aconf=?aconf, "LaunchingAgg");
+
+            launch_agg(&rconf, &aconf).await?;
+
+            Ok(())
+        }
+        Some(("churn", sub_matches)) => {
+            let mut cconf = ChurnConfig {
+                out_dir: PathBuf::from("."),
+                window_blocks: 2,
+                min_repeats: 3,
+                lockstep_blocks: 5,
+                exposure_tolerance: Decimal::new(5, 2),
+                bot_score: 50,
+                funders: None,
+                min_funded: 3,
+            };
+            if let Some(wb_str) = sub_matches.get_one::<String>("window_blocks") {
+                cconf.window_blocks = wb_str.parse()?;
+            }
+            if let Some(mr_str) = sub_matches.get_one::<String>("min_repeats") {
+                cconf.min_repeats = mr_str.parse()?;
+            }
+            if let Some(lb_str) = sub_matches.get_one::<String>("lockstep_blocks") {
+                cconf.lockstep_blocks = lb_str.parse()?;
+            }
+            if let Some(et_str) = sub_matches.get_one::<String>("exposure_tolerance") {
+                cconf.exposure_tolerance = et_str.parse()?;
+            }
+            if let Some(bs_str) = sub_matches.get_one::<String>("bot_score") {
+                cconf.bot_score = bs_str.parse()?;
+            }
+            if let Some(funding_path) = sub_matches.get_one::<String>("funding") {
+                cconf.funders = Some(read_funders(Path::new(funding_path))?);
+            }
+            if let Some(mf_str) = sub_matches.get_one::<String>("min_funded") {
+                cconf.min_funded = mf_str.parse()?;
+            }
+
+            tracing::info!(cconf=?cconf, "LaunchingChurn");
+
+            launch_churn(client.clone(), &cconf).await
+        }
+        Some(("maturities", sub_matches)) => {
+            let mut mconf = MaturitiesConfig {
+                out_dir: PathBuf::from("."),
+                horizon_days: 7,
+            };
+            if let Some(days_str) = sub_matches.get_one::<String>("days") {
+                mconf.horizon_days = days_str.parse()?;
+            }
+
+            let (at_block_num, at_timestamp) = match sub_matches.get_one::<String>("date") {
+                Some(date_str) => {
+                    let datetime = NaiveDate::parse_from_str(date_str, "%Y-%m-%d")?
+                        .and_hms_opt(0, 0, 0)
+                        .unwrap()
+                        .and_utc();
+                    let timestamp: u64 = datetime.timestamp().try_into().unwrap();
+                    let earliest_deploy_block_num = HYPERDRIVES
+                        .values()
+                        .min_by_key(|h| h.deploy_block_num)
+                        .unwrap()
+                        .deploy_block_num;
+                    let block_num = find_block_by_timestamp(
+                        client.clone(),
+                        timestamp,
+                        earliest_deploy_block_num,
+                        latest_block_num,
+                    )
+                    .await?;
+                    (block_num, timestamp.into())
+                }
+                None => {
+                    let block_num = eventsdbs_last_block_num()?;
+                    let Some(block) = client.get_block(block_num).await? else {
+                        bail!("block {} not found", block_num);
+                    };
+                    (block_num, block.timestamp)
+                }
+            };
+
+            tracing::info!(mconf=?mconf, at_block_num=?at_block_num, "LaunchingMaturities");
+
+            launch_maturities(client.clone(), &mconf, at_block_num, at_timestamp).await
+        }
+        Some(("reconcile", sub_matches)) => {
+            let mut rconf = ReconcileConfig {
+                out_dir: PathBuf::from("."),
+                tolerance: Decimal::new(1, 4),
+                samples: 10,
+            };
+            if let Some(tolerance_str) = sub_matches.get_one::<String>("tolerance") {
+                rconf.tolerance = tolerance_str.parse()?;
+            }
+            if let Some(samples_str) = sub_matches.get_one::<String>("samples") {
+                rconf.samples = samples_str.parse()?;
+            }
+
+            let (at_block_num, at_timestamp) = match sub_matches.get_one::<String>("block") {
+                Some(block_str) => {
+                    let block_num: U64 = block_str.parse::<u64>()?.into();
+                    let Some(block) = client.get_block(block_num).await? else {
+                        bail!("block {} not found", block_num);
+                    };
+                    (block_num, block.timestamp)
+                }
+                None => {
+                    let block_num = eventsdbs_last_block_num()?;
+                    let Some(block) = client.get_block(block_num).await? else {
+                        bail!("block {} not found", block_num);
+                    };
+                    (block_num, block.timestamp)
+                }
+            };
+
+            tracing::info!(rconf=?rconf, at_block_num=?at_block_num, "LaunchingReconcile");
 
-            launch_agg(&rconf).await
+            launch_reconcile(client.clone(), &rconf, at_block_num, at_timestamp).await
         }
         _ => bail!("Invalid subcommand"),
     }
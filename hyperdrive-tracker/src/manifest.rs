@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use eyre::Result;
+use sha2::{Digest, Sha256};
+
+use crate::globals::*;
+use crate::types::*;
+use crate::utils::*;
+
+pub const MANIFEST_FILENAME: &str = "manifest.json";
+
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let bytes = fs::read(path)?;
+    Ok(format!("{:x}", Sha256::digest(bytes)))
+}
+
+///Hashes the events DB of every pool deployed before `rconf.end_block_num`, and every file the agg
+///options point to.
+pub fn hash_inputs(rconf: &RunConfig, aconf: &AggConfig) -> Result<BTreeMap<String, String>> {
+    let mut inputs = BTreeMap::new();
+    for hconf in HYPERDRIVES
+        .values()
+        .filter(|hc| hc.deploy_block_num < rconf.end_block_num)
+    {
+        let filename = eventsdb_filename(hconf);
+        let hash = sha256_file(Path::new(&filename))?;
+        inputs.insert(filename, hash);
+    }
+    for filename in aconf.input_filenames() {
+        let hash = sha256_file(Path::new(filename))?;
+        inputs.insert(filename.to_string(), hash);
+    }
+    Ok(inputs)
+}
+
+///Hashes output files, keyed by file name so manifests written to different dirs compare equal.
+pub fn hash_outputs(out_dir: &Path, filenames: &[&str]) -> Result<BTreeMap<String, String>> {
+    let mut outputs = BTreeMap::new();
+    for filename in filenames {
+        let hash = sha256_file(&out_dir.join(filename))?;
+        outputs.insert(filename.to_string(), hash);
+    }
+    Ok(outputs)
+}
+
+pub fn read_manifest(dir: &Path) -> Result<Manifest> {
+    let json_str = fs::read_to_string(dir.join(MANIFEST_FILENAME))?;
+    Ok(serde_json::from_str(&json_str)?)
+}
+
+pub fn write_manifest(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let json_str = serde_json::to_string_pretty(manifest)?;
+    let mut file = fs::File::create(dir.join(MANIFEST_FILENAME))?;
+    file.write_all(json_str.as_bytes())?;
+    Ok(())
+}
+
+///Lists every difference between two manifests, empty if they match.
+pub fn diff_manifests(expected: &Manifest, actual: &Manifest) -> Vec<String> {
+    let mut diffs = Vec::new();
+
+    if expected.start_block_num != actual.start_block_num
+        || expected.end_block_num != actual.end_block_num
+    {
+        diffs.push(format!(
+            "block range: expected {}..{} got {}..{}",
+            expected.start_block_num,
+            expected.end_block_num,
+            actual.start_block_num,
+            actual.end_block_num
+        ));
+    }
+
+    if expected.options != actual.options {
+        diffs.push(format!(
+            "options: expected {:?} got {:?}",
+            expected.options, actual.options
+        ));
+    }
+
+    for (kind, expected_hashes, actual_hashes) in [
+        ("input", &expected.inputs, &actual.inputs),
+        ("output", &expected.outputs, &actual.outputs),
+    ] {
+        for (filename, expected_hash) in expected_hashes {
+            match actual_hashes.get(filename) {
+                Some(actual_hash) if actual_hash == expected_hash => (),
+                Some(actual_hash) => diffs.push(format!(
+                    "{} {}: expected {} got {}",
+                    kind, filename, expected_hash, actual_hash
+                )),
+                None => diffs.push(format!("{} {}: missing", kind, filename)),
+            }
+        }
+        for filename in actual_hashes.keys() {
+            if !expected_hashes.contains_key(filename) {
+                diffs.push(format!("{} {}: unexpected", kind, filename));
+            }
+        }
+    }
+
+    diffs
+}
@@ -0,0 +1,193 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::sync::Arc;
+
+use csv::Writer;
+use ethers::{
+    providers::{Provider, Ws},
+    types::{H160, I256, U256, U64},
+};
+use eyre::Result;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use hyperdrive_wrappers::wrappers::ihyperdrive::i_hyperdrive;
+
+use crate::globals::*;
+use crate::types::*;
+use crate::utils::*;
+
+pub const MATURITIES_FILENAME: &str = "maturities.csv";
+pub const MATURITY_HOLDERS_FILENAME: &str = "maturity_holders.csv";
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum MaturityStatus {
+    ///Maturing within the horizon.
+    Maturing,
+    ///Matured but still held, hence unclosed.
+    Matured,
+}
+
+///Outstanding bonds of one maturity checkpoint. Normalized.
+#[derive(Debug, Clone, Default)]
+struct MaturityBucket {
+    longs_outstanding: Decimal,
+    shorts_outstanding: Decimal,
+    long_holders: usize,
+    short_holders: usize,
+}
+
+#[derive(Serialize)]
+struct MaturityCsvRecord {
+    timestamp: String,
+    block_number: u64,
+    pool_type: String,
+    pool_address: H160,
+    maturity_time: String,
+    matured: bool,
+    longs_outstanding: String,
+    shorts_outstanding: String,
+    long_holders: usize,
+    short_holders: usize,
+}
+
+#[derive(Serialize)]
+struct MaturityHolderCsvRecord {
+    timestamp: String,
+    block_number: u64,
+    pool_type: String,
+    pool_address: H160,
+    position_type: PositionType,
+    user_address: H160,
+    maturity_time: String,
+    status: MaturityStatus,
+    ///Negative once matured.
+    days_to_maturity: i64,
+    bond_amount: String,
+}
+
+///Bonds held per position at timestamp, leaving out closed positions.
+fn outstanding_bonds(
+    positions: &HashMap<PositionKey, Vec<PositionDebit>>,
+    at_timestamp: U256,
+) -> BTreeMap<(U256, H160), Decimal> {
+    positions
+        .iter()
+        .map(|(key, debits)| {
+            let bond_amount: I256 = debits
+                .iter()
+                .filter(|debit| debit.timestamp < at_timestamp)
+                .map(|debit| debit.bond_amount)
+                .fold(I256::zero(), |acc, amount| acc + amount);
+            ((key.maturity_time, key.trader), bond_amount.normalized())
+        })
+        .filter(|(_, bond_amount)| *bond_amount > Decimal::ZERO)
+        .collect()
+}
+
+fn days_to_maturity(maturity_time: U256, at_timestamp: U256) -> i64 {
+    let maturity_time = maturity_time.as_u64() as i64;
+    let at_timestamp = at_timestamp.as_u64() as i64;
+    (maturity_time - at_timestamp).div_euclid(SECONDS_PER_DAY as i64)
+}
+
+///Writes outstanding bonds per pool and maturity checkpoint as of `at_timestamp`, along with the
+///holders of positions maturing within the horizon or matured and still unclosed. Fails if an
+///events DB does not reach `at_block_num`.
+pub async fn launch_maturities(
+    client: Arc<Provider<Ws>>,
+    mconf: &MaturitiesConfig,
+    at_block_num: U64,
+    at_timestamp: U256,
+) -> Result<()> {
+    let mut maturities_writer = Writer::from_path(mconf.out_dir.join(MATURITIES_FILENAME))?;
+    let mut holders_writer = Writer::from_path(mconf.out_dir.join(MATURITY_HOLDERS_FILENAME))?;
+    let horizon_end = at_timestamp + U256::from(mconf.horizon_days * SECONDS_PER_DAY);
+
+    let mut hconfs: Vec<&HyperdriveConfig> = HYPERDRIVES
+        .values()
+        .filter(|hc| hc.deploy_block_num < at_block_num)
+        .collect();
+    hconfs.sort_by_key(|hc| hc.address);
+
+    for hconf in hconfs {
+        let json_str = fs::read_to_string(eventsdb_filename(hconf))?;
+        let events_db: EventsDb = serde_json::from_str(&json_str)?;
+        ensure_eventsdb_covers(hconf, &events_db, at_block_num)?;
+        let contract = i_hyperdrive::IHyperdrive::new(hconf.address, client.clone());
+        let pool_config = contract.get_pool_config().call().await?;
+        let base_decimals =
+            read_base_decimals(client.clone(), hconf, pool_config.base_token).await?;
+        let events = events_db.events.to_wad(base_decimals);
+
+        tracing::info!(
+            pool_type = hconf.pool_type,
+            address=?hconf.address,
+            at_timestamp=?at_timestamp,
+            "ReportingMaturities"
+        );
+
+        let mut buckets: BTreeMap<U256, MaturityBucket> = BTreeMap::new();
+        for (position_type, positions) in [
+            (PositionType::Long, &events.longs),
+            (PositionType::Short, &events.shorts),
+        ] {
+            for ((maturity_time, trader), bond_amount) in outstanding_bonds(positions, at_timestamp)
+            {
+                let bucket = buckets.entry(maturity_time).or_default();
+                match position_type {
+                    PositionType::Long => {
+                        bucket.longs_outstanding += bond_amount;
+                        bucket.long_holders += 1;
+                    }
+                    _ => {
+                        bucket.shorts_outstanding += bond_amount;
+                        bucket.short_holders += 1;
+                    }
+                }
+
+                let status = if maturity_time <= at_timestamp {
+                    MaturityStatus::Matured
+                } else if maturity_time <= horizon_end {
+                    MaturityStatus::Maturing
+                } else {
+                    continue;
+                };
+                holders_writer.serialize(MaturityHolderCsvRecord {
+                    timestamp: timestamp_to_string(at_timestamp),
+                    block_number: at_block_num.as_u64(),
+                    pool_type: hconf.pool_type.to_string(),
+                    pool_address: hconf.address,
+                    position_type,
+                    user_address: trader,
+                    maturity_time: timestamp_to_string(maturity_time),
+                    status,
+                    days_to_maturity: days_to_maturity(maturity_time, at_timestamp),
+                    bond_amount: bond_amount.compact_ser(),
+                })?
+            }
+        }
+
+        for (maturity_time, bucket) in buckets {
+            maturities_writer.serialize(MaturityCsvRecord {
+                timestamp: timestamp_to_string(at_timestamp),
+                block_number: at_block_num.as_u64(),
+                pool_type: hconf.pool_type.to_string(),
+                pool_address: hconf.address,
+                maturity_time: timestamp_to_string(maturity_time),
+                matured: maturity_time <= at_timestamp,
+                longs_outstanding: bucket.longs_outstanding.compact_ser(),
+                shorts_outstanding: bucket.shorts_outstanding.compact_ser(),
+                long_holders: bucket.long_holders,
+                short_holders: bucket.short_holders,
+            })?
+        }
+    }
+
+    maturities_writer.flush()?;
+    holders_writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,383 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::sync::Arc;
+
+use csv::Writer;
+use ethers::{
+    providers::{Provider, Ws},
+    types::{H160, I256, U256, U64},
+};
+use eyre::{bail, Result};
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use hyperdrive_wrappers::wrappers::ihyperdrive::i_hyperdrive;
+
+use crate::globals::*;
+use crate::types::*;
+use crate::utils::*;
+
+pub const RECONCILE_FILENAME: &str = "reconcile.csv";
+
+///ERC-1155 asset ID prefixes of Hyperdrive, the maturity time filling the lower bits.
+const LONG_ASSET_PREFIX: u64 = 1;
+const SHORT_ASSET_PREFIX: u64 = 2;
+const ASSET_PREFIX_SHIFT: usize = 248;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ReconcileCheck {
+    LongsOutstanding,
+    ShortsOutstanding,
+    LpTotalSupply,
+    Balance,
+}
+
+#[derive(Serialize)]
+struct ReconcileCsvRecord {
+    timestamp: String,
+    block_number: u64,
+    pool_type: String,
+    pool_address: H160,
+    check: ReconcileCheck,
+    position_type: Option<PositionType>,
+    holder: Option<H160>,
+    maturity_time: Option<String>,
+    reconstructed: String,
+    onchain: String,
+    difference: String,
+    within_tolerance: bool,
+    attributed: bool,
+}
+
+///One reconstructed amount against its on-chain counterpart. Normalized.
+struct Reconciliation {
+    check: ReconcileCheck,
+    position_type: Option<PositionType>,
+    holder: Option<H160>,
+    maturity_time: Option<U256>,
+    reconstructed: Decimal,
+    onchain: Decimal,
+    ///Holding credited through a router, which the holder does not own on-chain.
+    attributed: bool,
+}
+
+impl Reconciliation {
+    fn difference(&self) -> Decimal {
+        self.reconstructed - self.onchain
+    }
+
+    ///Relative to the largest of both amounts, so that two zeros match.
+    fn within_tolerance(&self, tolerance: Decimal) -> bool {
+        let reference = self.reconstructed.abs().max(self.onchain.abs());
+        self.difference().abs() <= reference * tolerance
+    }
+}
+
+fn asset_id(position_type: PositionType, maturity_time: U256) -> U256 {
+    let prefix = match position_type {
+        PositionType::Long => LONG_ASSET_PREFIX,
+        PositionType::Short => SHORT_ASSET_PREFIX,
+        PositionType::Lp => return U256::zero(),
+    };
+    (U256::from(prefix) << ASSET_PREFIX_SHIFT) | maturity_time
+}
+
+///Reconstructed balance of each key as of the end of `at_block_num`, raw base token units.
+fn balances_at<K: Copy + Eq + std::hash::Hash>(
+    debits: impl Iterator<Item = (K, Vec<(U64, I256)>)>,
+    at_block_num: U64,
+) -> HashMap<K, I256> {
+    debits
+        .map(|(key, amounts)| {
+            let balance = amounts
+                .iter()
+                .filter(|(block_number, _)| *block_number <= at_block_num)
+                .fold(I256::zero(), |acc, (_, amount)| acc + *amount);
+            (key, balance)
+        })
+        .collect()
+}
+
+///Reconciles the positions reconstructed from the events DB of one pool with its contract:
+///* bonds of longs and shorts against `longs_outstanding` and `shorts_outstanding`, which keep
+///  matured positions until their maturity checkpoint is minted;
+///* LP shares, plus those locked at the zero address on initialization, against the ERC-1155
+///  total supply of the LP asset, since `lp_total_supply` also counts withdrawal shares;
+///* the `samples` largest holdings of each position type against `balanceOf`.
+///
+///Holdings credited through routers are owned on-chain by the routers, so they are marked as
+///attributed. ERC-1155 transfers also make holders differ from the token owners, discrepancies of
+///holdings are to be read with that in mind.
+async fn reconcile_pool(
+    client: Arc<Provider<Ws>>,
+    rconf: &ReconcileConfig,
+    hconf: &HyperdriveConfig,
+    sevents: &SerializableEvents,
+    at_block_num: U64,
+    at_timestamp: U256,
+) -> Result<Vec<Reconciliation>> {
+    let contract = i_hyperdrive::IHyperdrive::new(hconf.address, client.clone());
+    let pool_config = contract.get_pool_config().call().await?;
+    let base_decimals = read_base_decimals(client.clone(), hconf, pool_config.base_token).await?;
+    let pool_info = contract.get_pool_info().block(at_block_num).call().await?;
+
+    let position_debits = |positions: &HashMap<PositionKey, Vec<PositionDebit>>| {
+        positions
+            .iter()
+            .map(|(key, debits)| {
+                let amounts = debits
+                    .iter()
+                    .map(|debit| (debit.block_number, debit.bond_amount))
+                    .collect();
+                (*key, amounts)
+            })
+            .collect::<Vec<_>>()
+    };
+    let longs = balances_at(position_debits(&sevents.longs).into_iter(), at_block_num);
+    let shorts = balances_at(position_debits(&sevents.shorts).into_iter(), at_block_num);
+    let lps = balances_at(
+        sevents.lps.iter().map(|(key, debits)| {
+            let amounts = debits
+                .iter()
+                .map(|debit| (debit.block_number, debit.lp_amount))
+                .collect();
+            (*key, amounts)
+        }),
+        at_block_num,
+    );
+
+    let mut reconciliations: Vec<Reconciliation> = vec![];
+
+    let matured_maturities: BTreeSet<U256> = longs
+        .iter()
+        .chain(shorts.iter())
+        .filter(|(key, balance)| key.maturity_time <= at_timestamp && !balance.is_zero())
+        .map(|(key, _)| key.maturity_time)
+        .collect();
+    let mut unminted_maturities: BTreeSet<U256> = BTreeSet::new();
+    for maturity_time in matured_maturities {
+        let checkpoint = contract
+            .get_checkpoint(maturity_time)
+            .block(at_block_num)
+            .call()
+            .await?;
+        if checkpoint.vault_share_price == 0 {
+            unminted_maturities.insert(maturity_time);
+        }
+    }
+    let outstanding_at = |maturity_time: U256| {
+        maturity_time > at_timestamp || unminted_maturities.contains(&maturity_time)
+    };
+
+    for (check, position_type, balances, outstanding) in [
+        (
+            ReconcileCheck::LongsOutstanding,
+            PositionType::Long,
+            &longs,
+            pool_info.longs_outstanding,
+        ),
+        (
+            ReconcileCheck::ShortsOutstanding,
+            PositionType::Short,
+            &shorts,
+            pool_info.shorts_outstanding,
+        ),
+    ] {
+        let reconstructed = balances
+            .iter()
+            .filter(|(key, _)| outstanding_at(key.maturity_time))
+            .fold(I256::zero(), |acc, (_, balance)| acc + *balance);
+        reconciliations.push(Reconciliation {
+            check,
+            position_type: Some(position_type),
+            holder: None,
+            maturity_time: None,
+            reconstructed: reconstructed.scaled(base_decimals),
+            onchain: outstanding.scaled(base_decimals),
+            attributed: false,
+        });
+    }
+
+    let lp_asset_id = asset_id(PositionType::Lp, U256::zero());
+    let locked_lp_shares = contract
+        .balance_of(lp_asset_id, H160::zero())
+        .block(at_block_num)
+        .call()
+        .await?;
+    let lp_total_supply = contract
+        .total_supply(lp_asset_id)
+        .block(at_block_num)
+        .call()
+        .await?;
+    let reconstructed_lp_shares = lps
+        .values()
+        .fold(I256::zero(), |acc, balance| acc + *balance);
+    reconciliations.push(Reconciliation {
+        check: ReconcileCheck::LpTotalSupply,
+        position_type: Some(PositionType::Lp),
+        holder: None,
+        maturity_time: None,
+        reconstructed: reconstructed_lp_shares.scaled(base_decimals)
+            + locked_lp_shares.scaled(base_decimals),
+        onchain: lp_total_supply.scaled(base_decimals),
+        attributed: false,
+    });
+
+    let mut holdings: Vec<(PositionType, H160, U256, I256)> = vec![];
+    for (position_type, balances) in [(PositionType::Long, &longs), (PositionType::Short, &shorts)]
+    {
+        let mut largest: Vec<_> = balances
+            .iter()
+            .filter(|(_, balance)| **balance > I256::zero())
+            .map(|(key, balance)| (position_type, key.trader, key.maturity_time, *balance))
+            .collect();
+        largest.sort_by_key(|(_, trader, maturity_time, balance)| {
+            (std::cmp::Reverse(*balance), *trader, *maturity_time)
+        });
+        holdings.extend(largest.into_iter().take(rconf.samples));
+    }
+    let mut largest_lps: Vec<_> = lps
+        .iter()
+        .filter(|(_, balance)| **balance > I256::zero())
+        .map(|(key, balance)| (PositionType::Lp, key.provider, U256::zero(), *balance))
+        .collect();
+    largest_lps.sort_by_key(|(_, provider, _, balance)| (std::cmp::Reverse(*balance), *provider));
+    holdings.extend(largest_lps.into_iter().take(rconf.samples));
+
+    let router_credited: BTreeSet<(PositionType, H160, U256)> = [
+        (PositionType::Long, &sevents.router_longs),
+        (PositionType::Short, &sevents.router_shorts),
+    ]
+    .into_iter()
+    .flat_map(|(position_type, router_positions)| {
+        router_positions.iter().flat_map(move |(key, owners)| {
+            owners
+                .iter()
+                .map(move |owner| (position_type, *owner, key.maturity_time))
+        })
+    })
+    .chain(sevents.router_lps.values().flat_map(|owners| {
+        owners
+            .iter()
+            .map(|owner| (PositionType::Lp, *owner, U256::zero()))
+    }))
+    .collect();
+
+    for (position_type, holder, maturity_time, balance) in holdings {
+        let onchain = contract
+            .balance_of(asset_id(position_type, maturity_time), holder)
+            .block(at_block_num)
+            .call()
+            .await?;
+        reconciliations.push(Reconciliation {
+            check: ReconcileCheck::Balance,
+            position_type: Some(position_type),
+            holder: Some(holder),
+            maturity_time: match position_type {
+                PositionType::Lp => None,
+                _ => Some(maturity_time),
+            },
+            reconstructed: balance.scaled(base_decimals),
+            onchain: onchain.scaled(base_decimals),
+            attributed: router_credited.contains(&(position_type, holder, maturity_time)),
+        });
+    }
+
+    Ok(reconciliations)
+}
+
+///Writes every reconciliation of every pool deployed by `at_block_num`, then fails if any of
+///them is off by more than the tolerance, attributed holdings aside.
+pub async fn launch_reconcile(
+    client: Arc<Provider<Ws>>,
+    rconf: &ReconcileConfig,
+    at_block_num: U64,
+    at_timestamp: U256,
+) -> Result<()> {
+    let mut reconcile_writer = Writer::from_path(rconf.out_dir.join(RECONCILE_FILENAME))?;
+    let mut discrepancy_count = 0;
+
+    let mut hconfs: Vec<&HyperdriveConfig> = HYPERDRIVES
+        .values()
+        .filter(|hc| hc.deploy_block_num < at_block_num)
+        .collect();
+    hconfs.sort_by_key(|hc| hc.address);
+
+    for hconf in hconfs {
+        let json_str = fs::read_to_string(eventsdb_filename(hconf))?;
+        let events_db: EventsDb = serde_json::from_str(&json_str)?;
+        ensure_eventsdb_covers(hconf, &events_db, at_block_num)?;
+
+        tracing::info!(
+            pool_type = hconf.pool_type,
+            address=?hconf.address,
+            at_block_num=?at_block_num,
+            "Reconciling"
+        );
+
+        let reconciliations = reconcile_pool(
+            client.clone(),
+            rconf,
+            hconf,
+            &events_db.events,
+            at_block_num,
+            at_timestamp,
+        )
+        .await?;
+
+        for reconciliation in reconciliations {
+            let within_tolerance = reconciliation.within_tolerance(rconf.tolerance);
+            if !within_tolerance && reconciliation.attributed {
+                tracing::info!(
+                    pool_type = hconf.pool_type,
+                    address=?hconf.address,
+                    position_type=?reconciliation.position_type,
+                    holder=?reconciliation.holder,
+                    maturity_time=?reconciliation.maturity_time,
+                    difference=%reconciliation.difference(),
+                    "ReconcileAttributedDiscrepancy"
+                );
+            } else if !within_tolerance {
+                discrepancy_count += 1;
+                tracing::warn!(
+                    pool_type = hconf.pool_type,
+                    address=?hconf.address,
+                    check=?reconciliation.check,
+                    position_type=?reconciliation.position_type,
+                    holder=?reconciliation.holder,
+                    maturity_time=?reconciliation.maturity_time,
+                    reconstructed=%reconciliation.reconstructed,
+                    onchain=%reconciliation.onchain,
+                    difference=%reconciliation.difference(),
+                    "ReconcileDiscrepancy"
+                );
+            }
+            reconcile_writer.serialize(ReconcileCsvRecord {
+                timestamp: timestamp_to_string(at_timestamp),
+                block_number: at_block_num.as_u64(),
+                pool_type: hconf.pool_type.to_string(),
+                pool_address: hconf.address,
+                check: reconciliation.check,
+                position_type: reconciliation.position_type,
+                holder: reconciliation.holder,
+                maturity_time: reconciliation.maturity_time.map(timestamp_to_string),
+                reconstructed: reconciliation.reconstructed.compact_ser(),
+                onchain: reconciliation.onchain.compact_ser(),
+                difference: reconciliation.difference().compact_ser(),
+                within_tolerance,
+                attributed: reconciliation.attributed,
+            })?
+        }
+    }
+
+    reconcile_writer.flush()?;
+    if discrepancy_count > 0 {
+        bail!(
+            "{} reconciliations off by more than {}",
+            discrepancy_count,
+            rconf.tolerance
+        );
+    }
+    Ok(())
+}
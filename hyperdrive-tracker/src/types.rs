@@ -1,13 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use dashmap::DashMap;
 use ethers::{
     providers::{Provider, Ws},
-    types::{H160, I256, U256, U64},
+    types::{H160, H256, I256, U256, U64},
 };
 use serde::{Deserialize, Serialize};
 
+use rust_decimal::Decimal;
+
 use hyperdrive_wrappers::wrappers::ihyperdrive::i_hyperdrive;
 
 #[derive(Debug, Clone, Copy)]
@@ -15,6 +18,8 @@ pub struct HyperdriveConfig {
     pub pool_type: &'static str,
     pub address: H160,
     pub deploy_block_num: U64,
+    ///Decimals of the base token, read from the token contract when unset.
+    pub base_decimals: Option<u32>,
 }
 
 #[derive(Debug, Clone)]
@@ -22,6 +27,8 @@ pub struct SingleTrackerConfig {
     pub hconf: &'static HyperdriveConfig,
     pub contract: i_hyperdrive::IHyperdrive<Provider<Ws>>,
     pub pool_config: i_hyperdrive::PoolConfig,
+    ///Decimals of the base token, which also denominate bonds and LP shares.
+    pub base_decimals: u32,
 }
 
 #[derive(Debug, Clone)]
@@ -32,6 +39,172 @@ pub struct RunConfig {
     pub end_block_num: U64,
 }
 
+#[derive(Debug, Clone)]
+pub struct AggConfig {
+    pub out_dir: PathBuf,
+    pub group_by: GroupBy,
+    pub valuation: Valuation,
+    pub cost_basis: CostBasis,
+    ///Optional per-position statements file name, within `out_dir`.
+    pub positions_out: Option<String>,
+    ///Optional opening trades fixed rates file name, within `out_dir`.
+    pub rates_out: Option<String>,
+    ///Optional per-lot realized closes file name, within `out_dir`.
+    pub lots_out: Option<String>,
+    ///Users whose time-weighted capital is below this are flagged, normalized base amount.
+    ///Never flagged when unset, negative net capital included.
+    pub min_capital: Option<Decimal>,
+    ///Lots closed sooner than this many seconds count towards volume as per `holding_weighting`.
+    pub min_holding: Option<u64>,
+    pub holding_weighting: HoldingWeighting,
+    ///Wallets to aggregate as one participant.
+    pub identities: Option<Identities>,
+    ///Activity of addresses it rejects is aggregated into a single excluded bucket.
+    pub address_filter: AddressFilter,
+    ///Registered addresses, the only ones rows are written for when set.
+    pub participants: Option<BTreeSet<H160>>,
+    ///Base token prices to add USD columns with.
+    pub prices: Option<PriceBook>,
+    ///Command line options it was built from, recorded in the manifest to re-run the same way.
+    pub options: BTreeMap<String, String>,
+}
+
+///USD prices of base tokens, each applying from its timestamp until the next one.
+#[derive(Debug, Clone, Default)]
+pub struct PriceBook {
+    pub prices: BTreeMap<H160, BTreeMap<u64, Decimal>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AcqConfig {
+    ///Contracts trading on behalf of players, whose events are credited as per `attribution`.
+    pub routers: Vec<AddressPattern>,
+    pub attribution: Attribution,
+    ///Record the gas of every event transaction, at the cost of a receipt query each. Charged once
+    ///per transaction, to the event account only if it sent the transaction. Gas of transactions
+    ///whose events were all dropped is kept apart as unattributed.
+    pub fetch_receipts: bool,
+    ///Estimate the fees of every trade, at the cost of a pool info query each.
+    pub estimate_fees: bool,
+}
+
+///Whom events sent by a router are credited to: the sender of their transaction, or for closes
+///and liquidity removals their destination, unless it is a router too. Closes fall back to the
+///players credited with the router position when that account holds none of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Attribution {
+    #[default]
+    TxFrom,
+    Destination,
+}
+
+///An address, or a hex prefix of addresses like `0xdead*`, stored lowercase without `0x`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressPattern {
+    Exact(H160),
+    Prefix(String),
+}
+
+///Accepts addresses matching the allowlist, any if empty, and none of the denylist.
+#[derive(Debug, Clone, Default)]
+pub struct AddressFilter {
+    pub allow: Vec<AddressPattern>,
+    pub deny: Vec<AddressPattern>,
+}
+
+///Participant of each address, from an identity mapping file. A participant is keyed in the
+///output by its lowest member address.
+#[derive(Debug, Clone, Default)]
+pub struct Identities {
+    pub participants: BTreeMap<H160, String>,
+    pub primary_addresses: BTreeMap<String, H160>,
+    ///Addresses mapped to more than one participant, the first of which applies.
+    pub conflicts: BTreeMap<H160, Vec<String>>,
+}
+
+///How the volume of lots held less than the minimum holding time is counted: not at all, or
+///weighted by the fraction of the minimum they were held for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HoldingWeighting {
+    Exclude,
+    ProRata,
+}
+
+#[derive(Debug, Clone)]
+pub struct MaturitiesConfig {
+    pub out_dir: PathBuf,
+    ///Holders of positions maturing within this many days are listed.
+    pub horizon_days: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChurnConfig {
+    pub out_dir: PathBuf,
+    ///Blocks within which an open and its close make a round trip, or opposite opens a hedge.
+    pub window_blocks: u64,
+    ///Occurrences from which round trips or hedges are suspicious.
+    pub min_repeats: usize,
+    ///Blocks two wallets must both trade in to be deemed trading in lockstep.
+    pub lockstep_blocks: usize,
+    ///Relative bond amount difference under which opposite opens cancel out.
+    pub exposure_tolerance: Decimal,
+    ///Risk score from which an address is flagged as a bot.
+    pub bot_score: u32,
+    ///Funder of each wallet, from a funding file, to cluster wallets funded by a common source.
+    pub funders: Option<BTreeMap<H160, H160>>,
+    ///Trading wallets sharing a funder from which they form a cluster.
+    pub min_funded: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReconcileConfig {
+    pub out_dir: PathBuf,
+    ///Relative difference above which a reconstructed amount does not match the contract.
+    pub tolerance: Decimal,
+    ///Largest holdings per pool and position type checked against `balanceOf`.
+    pub samples: usize,
+}
+
+///How open positions are valued at period end: as if held until maturity, or as if closed at
+///period end against the pool (curve slippage and fees included).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Valuation {
+    Maturity,
+    Market,
+}
+
+///How closes are matched against the lots (opens) of a position to realize PnL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CostBasis {
+    Fifo,
+    AverageCost,
+}
+
+///Level at which per-pool user aggregates are merged into output rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupBy {
+    Address,
+    PoolType,
+    BaseToken,
+    All,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum PositionType {
+    Long,
+    Short,
+    Lp,
+}
+
+///Fields not relevant to the grouping level are left empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PoolGroupKey {
+    pub pool_type: Option<&'static str>,
+    pub pool_address: Option<H160>,
+    pub base_token: Option<H160>,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct PositionKey {
     pub trader: H160,
@@ -42,13 +215,47 @@ pub struct PositionKey {
 ///base-token holdings.
 pub type Long = Vec<PositionDebit>;
 
-///Closes are negative, Opens are positive.
+///Closes are negative, Opens are positive. Opens record the fixed rate implied by their base and
+///bond amounts, which events DBs acquired before it was tracked lack.
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub struct PositionDebit {
     pub block_number: U64,
     pub timestamp: U256,
     pub base_amount: I256,
     pub bond_amount: I256,
+    #[serde(default)]
+    pub fixed_rate: Option<Decimal>,
+    #[serde(default)]
+    pub gas: Option<GasCost>,
+    #[serde(default)]
+    pub fees: Option<TradeFees>,
+}
+
+///Fees paid on a trade as estimated from the pool state before it, normalized base amounts.
+///`governance` is the part of the curve and flat fees going to governance rather than to LPs.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct TradeFees {
+    pub curve: Decimal,
+    pub flat: Decimal,
+    pub governance: Decimal,
+}
+
+///Gas of the transaction a debit was recorded from, if receipts were fetched.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct GasCost {
+    pub gas_used: U256,
+    pub effective_gas_price: U256,
+    ///Missing from events DBs acquired before transactions were charged once across pools.
+    #[serde(default)]
+    pub transaction_hash: Option<H256>,
+}
+
+///Gas of a transaction whose events were all dropped, such as closes without a matching open.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct UnattributedGas {
+    pub block_number: U64,
+    pub timestamp: U256,
+    pub gas: GasCost,
 }
 
 pub type Short = Vec<PositionDebit>;
@@ -66,6 +273,8 @@ pub struct LpDebit {
     pub timestamp: U256,
     pub lp_amount: I256,
     pub base_amount: I256,
+    #[serde(default)]
+    pub gas: Option<GasCost>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
@@ -80,6 +289,11 @@ pub struct Events {
     pub shorts: DashMap<PositionKey, Short>,
     pub lps: DashMap<LpKey, Lp>,
     pub share_prices: DashMap<U256, SharePrice>,
+    ///Players credited with the positions held by routers, keyed by router, in order of opening.
+    pub router_longs: DashMap<PositionKey, Vec<H160>>,
+    pub router_shorts: DashMap<PositionKey, Vec<H160>>,
+    pub router_lps: DashMap<LpKey, Vec<H160>>,
+    pub unattributed_gas: DashMap<H256, UnattributedGas>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -88,6 +302,14 @@ pub struct SerializableEvents {
     pub shorts: HashMap<PositionKey, Short>,
     pub lps: HashMap<LpKey, Lp>,
     pub share_prices: HashMap<U256, SharePrice>,
+    #[serde(default)]
+    pub router_longs: HashMap<PositionKey, Vec<H160>>,
+    #[serde(default)]
+    pub router_shorts: HashMap<PositionKey, Vec<H160>>,
+    #[serde(default)]
+    pub router_lps: HashMap<LpKey, Vec<H160>>,
+    #[serde(default)]
+    pub unattributed_gas: HashMap<H256, UnattributedGas>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -95,3 +317,17 @@ pub struct EventsDb {
     pub end_block_num: u64,
     pub events: SerializableEvents,
 }
+
+///Hashes of everything an aggregation run read and wrote, to make reruns verifiable.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub start_block_num: u64,
+    pub end_block_num: u64,
+    ///Agg options of the run, `--check` re-runs with these rather than its own.
+    #[serde(default)]
+    pub options: BTreeMap<String, String>,
+    ///SHA-256 per events DB file name and per file given as an option.
+    pub inputs: BTreeMap<String, String>,
+    ///SHA-256 per output file name.
+    pub outputs: BTreeMap<String, String>,
+}
@@ -1,20 +1,24 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt;
 use std::fs;
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
-use chrono::{TimeZone, Utc};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
 use dashmap::DashMap;
 use ethers::{
     providers::{Middleware, Provider, Ws},
-    types::{H160, I256, U256, U64},
+    types::{transaction::eip2718::TypedTransaction, TransactionRequest, H160, I256, U256, U64},
 };
-use eyre::Result;
+use eyre::{eyre, Result};
 use rust_decimal::Decimal;
 use serde::de::{self, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+use hyperdrive_wrappers::wrappers::ihyperdrive::i_hyperdrive;
+
 use crate::globals::*;
 use crate::types::*;
 
@@ -58,36 +62,280 @@ pub async fn find_block_by_timestamp(
     Ok(res.into())
 }
 
+///Fixed APR of bonds bought at `price` base per bond and redeemed at par after `position_duration`.
+pub fn calc_fixed_rate(price: Decimal, position_duration: U256) -> Option<Decimal> {
+    if price <= Decimal::ZERO || position_duration.is_zero() {
+        return None;
+    }
+    let term = Decimal::from(position_duration.as_u64()) / Decimal::from(SECONDS_PER_YEAR);
+    Some(((Decimal::ONE - price) / (price * term)).round_dp(DECIMAL_PRECISION))
+}
+
+///A long pays `base_amount` for `bond_amount` bonds, both with 18 decimals.
+pub fn calc_long_fixed_rate(
+    base_amount: I256,
+    bond_amount: I256,
+    position_duration: U256,
+) -> Option<Decimal> {
+    // Dust rounds to zero once normalized.
+    let bond_amount = bond_amount.normalized();
+    if bond_amount <= Decimal::ZERO {
+        return None;
+    }
+    calc_fixed_rate(base_amount.normalized() / bond_amount, position_duration)
+}
+
+///A short deposits `base_amount` to sell `bond_amount` bonds, the bonds price being what the
+///deposit doesn't cover. Fees and prepaid interest are part of the deposit, so are priced in.
+///Both amounts with 18 decimals.
+pub fn calc_short_fixed_rate(
+    base_amount: I256,
+    bond_amount: I256,
+    position_duration: U256,
+) -> Option<Decimal> {
+    let bond_amount = bond_amount.normalized();
+    if bond_amount <= Decimal::ZERO {
+        return None;
+    }
+    calc_fixed_rate(
+        (bond_amount - base_amount.normalized()) / bond_amount,
+        position_duration,
+    )
+}
+
+///Fixed-point integers as decimals. `normalized` assumes 18 decimals, as for share prices, rates
+///and amounts of 18 decimals tokens, `scaled` takes the decimals of the token instead.
+///
+///Values are rounded half to even at `DECIMAL_PRECISION` digits in U256 arithmetic, no other
+///precision is lost. Values whose rounded mantissa does not fit a `Decimal` (about 7.9e28 whole
+///tokens at 0 decimals, 7.9e20 at `DECIMAL_PRECISION`) are errors with `try_scaled`. Otherwise
+///they count as zero, so that sums including them cannot overflow in turn, with a
+///`DecimalOverflow` warning and a `DECIMAL_OVERFLOWS` increment for outputs to be flagged.
 pub trait Decimalizable {
-    fn normalized(&self) -> Decimal;
+    fn try_scaled(&self, decimals: u32) -> Result<Decimal>;
+
+    fn scaled(&self, decimals: u32) -> Decimal {
+        self.try_scaled(decimals).unwrap_or_else(|err| {
+            tracing::warn!(err=%err, "DecimalOverflow");
+            DECIMAL_OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+            Decimal::ZERO
+        })
+    }
+
+    fn normalized(&self) -> Decimal {
+        self.scaled(DECIMAL_SCALE)
+    }
+}
+
+///Largest power of ten a U256 holds.
+const U256_MAX_EXP10: u32 = 77;
+
+///Rounds `abs` of `decimals` decimals to `DECIMAL_PRECISION` digits, then converts it to a
+///`Decimal` if its mantissa fits in 96 bits.
+fn decimal_from_sign_and_abs(negative: bool, abs: U256, decimals: u32) -> Result<Decimal> {
+    let (mantissa, scale) = if decimals <= DECIMAL_PRECISION {
+        (abs, decimals)
+    } else if decimals - DECIMAL_PRECISION > U256_MAX_EXP10 {
+        (U256::zero(), DECIMAL_PRECISION)
+    } else {
+        let divisor = U256::exp10((decimals - DECIMAL_PRECISION) as usize);
+        let (quotient, remainder) = abs.div_mod(divisor);
+        let twice_remainder = remainder.saturating_mul(U256::from(2));
+        let round_up = twice_remainder > divisor || (twice_remainder == divisor && quotient.bit(0));
+        let quotient = if round_up {
+            quotient + U256::one()
+        } else {
+            quotient
+        };
+        (quotient, DECIMAL_PRECISION)
+    };
+
+    if mantissa.bits() > 96 {
+        return Err(eyre!(
+            "{}{} at {} decimals overflows Decimal",
+            if negative { "-" } else { "" },
+            abs,
+            decimals
+        ));
+    }
+    let val_dec = Decimal::from_i128_with_scale(mantissa.as_u128() as i128, scale);
+    Ok(if negative { -val_dec } else { val_dec })
 }
 
 impl Decimalizable for I256 {
-    fn normalized(&self) -> Decimal {
-        let val_i128 = (*self).as_i128();
-        let val_dec = Decimal::from_i128_with_scale(val_i128, DECIMAL_SCALE);
-        val_dec.round_dp(DECIMAL_PRECISION)
+    fn try_scaled(&self, decimals: u32) -> Result<Decimal> {
+        let (sign, abs) = (*self).into_sign_and_abs();
+        decimal_from_sign_and_abs(sign.is_negative(), abs, decimals)
     }
 }
 
 impl Decimalizable for U256 {
-    fn normalized(&self) -> Decimal {
-        let val_ethers_i128 = I256::try_from(*self).unwrap();
-        let val_i128 = val_ethers_i128.as_i128();
-        let val_dec = Decimal::from_i128_with_scale(val_i128, DECIMAL_SCALE);
-        val_dec.round_dp(DECIMAL_PRECISION)
+    fn try_scaled(&self, decimals: u32) -> Result<Decimal> {
+        decimal_from_sign_and_abs(false, *self, decimals)
     }
 }
 
 impl Decimalizable for fixed_point::FixedPoint {
-    fn normalized(&self) -> Decimal {
-        let val_ethers_i128 = I256::try_from(*self).unwrap();
-        let val_i128 = val_ethers_i128.as_i128();
-        let val_dec = Decimal::from_i128_with_scale(val_i128, DECIMAL_SCALE);
-        val_dec.round_dp(DECIMAL_PRECISION)
+    fn try_scaled(&self, decimals: u32) -> Result<Decimal> {
+        decimal_from_sign_and_abs(false, U256::from(*self), decimals)
+    }
+}
+
+///Rescaling of token amounts from the decimals of the base token to 18 decimals, so that pools
+///are aggregated alike whatever their base token. Share prices and rates are left as they are.
+pub trait ToWad {
+    fn to_wad(self, decimals: u32) -> Self;
+}
+
+///Overflowing upscales count as zero, as overflowing `Decimalizable` conversions do, so that
+///sums including them cannot overflow in turn.
+fn wad_overflow<T: fmt::Display + Default>(value: T, decimals: u32) -> T {
+    tracing::warn!(value=%value, decimals=decimals, "WadOverflow");
+    DECIMAL_OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+    T::default()
+}
+
+impl ToWad for U256 {
+    fn to_wad(self, decimals: u32) -> Self {
+        if decimals <= DECIMAL_SCALE {
+            let factor = U256::exp10((DECIMAL_SCALE - decimals) as usize);
+            self.checked_mul(factor)
+                .unwrap_or_else(|| wad_overflow(self, decimals))
+        } else {
+            self / U256::exp10((decimals - DECIMAL_SCALE) as usize)
+        }
+    }
+}
+
+impl ToWad for I256 {
+    fn to_wad(self, decimals: u32) -> Self {
+        if decimals <= DECIMAL_SCALE {
+            let factor = I256::exp10((DECIMAL_SCALE - decimals) as usize);
+            self.checked_mul(factor)
+                .unwrap_or_else(|| wad_overflow(self, decimals))
+        } else {
+            self / I256::exp10((decimals - DECIMAL_SCALE) as usize)
+        }
+    }
+}
+
+impl ToWad for SerializableEvents {
+    fn to_wad(self, decimals: u32) -> Self {
+        let rescale_position = |debits: Vec<PositionDebit>| {
+            debits
+                .into_iter()
+                .map(|debit| PositionDebit {
+                    base_amount: debit.base_amount.to_wad(decimals),
+                    bond_amount: debit.bond_amount.to_wad(decimals),
+                    ..debit
+                })
+                .collect()
+        };
+        SerializableEvents {
+            longs: self
+                .longs
+                .into_iter()
+                .map(|(key, long)| (key, rescale_position(long)))
+                .collect(),
+            shorts: self
+                .shorts
+                .into_iter()
+                .map(|(key, short)| (key, rescale_position(short)))
+                .collect(),
+            lps: self
+                .lps
+                .into_iter()
+                .map(|(key, lp)| {
+                    let lp = lp
+                        .into_iter()
+                        .map(|debit| LpDebit {
+                            lp_amount: debit.lp_amount.to_wad(decimals),
+                            base_amount: debit.base_amount.to_wad(decimals),
+                            ..debit
+                        })
+                        .collect();
+                    (key, lp)
+                })
+                .collect(),
+            share_prices: self.share_prices,
+            router_longs: self.router_longs,
+            router_shorts: self.router_shorts,
+            router_lps: self.router_lps,
+            unattributed_gas: self.unattributed_gas,
+        }
+    }
+}
+
+impl ToWad for i_hyperdrive::PoolConfig {
+    fn to_wad(self, decimals: u32) -> Self {
+        i_hyperdrive::PoolConfig {
+            minimum_share_reserves: self.minimum_share_reserves.to_wad(decimals),
+            minimum_transaction_amount: self.minimum_transaction_amount.to_wad(decimals),
+            ..self
+        }
     }
 }
 
+impl ToWad for i_hyperdrive::PoolInfo {
+    fn to_wad(self, decimals: u32) -> Self {
+        i_hyperdrive::PoolInfo {
+            share_reserves: self.share_reserves.to_wad(decimals),
+            share_adjustment: self.share_adjustment.to_wad(decimals),
+            zombie_base_proceeds: self.zombie_base_proceeds.to_wad(decimals),
+            zombie_share_reserves: self.zombie_share_reserves.to_wad(decimals),
+            bond_reserves: self.bond_reserves.to_wad(decimals),
+            lp_total_supply: self.lp_total_supply.to_wad(decimals),
+            longs_outstanding: self.longs_outstanding.to_wad(decimals),
+            shorts_outstanding: self.shorts_outstanding.to_wad(decimals),
+            withdrawal_shares_ready_to_withdraw: self
+                .withdrawal_shares_ready_to_withdraw
+                .to_wad(decimals),
+            withdrawal_shares_proceeds: self.withdrawal_shares_proceeds.to_wad(decimals),
+            long_exposure: self.long_exposure.to_wad(decimals),
+            ..self
+        }
+    }
+}
+
+///Decimals of the pool base token: from the registry if set, 18 for ETH, else as read from the
+///token contract.
+pub async fn read_base_decimals(
+    client: Arc<Provider<Ws>>,
+    hconf: &HyperdriveConfig,
+    base_token: H160,
+) -> Result<u32> {
+    if let Some(decimals) = hconf.base_decimals {
+        return Ok(decimals);
+    }
+    if base_token == ETH_ADDRESS {
+        return Ok(DECIMAL_SCALE);
+    }
+
+    let tx: TypedTransaction = TransactionRequest::new()
+        .to(base_token)
+        .data(ERC20_DECIMALS_SELECTOR.to_vec())
+        .into();
+    let output = client.call(&tx, None).await?;
+    if output.len() < 32 {
+        return Err(eyre!(
+            "base token {:?} has no decimals(), set base_decimals in the registry",
+            base_token
+        ));
+    }
+    let decimals = U256::from_big_endian(&output[..32]);
+    if decimals > U256::from(U256_MAX_EXP10) {
+        return Err(eyre!(
+            "base token {:?} has {} decimals",
+            base_token,
+            decimals
+        ));
+    }
+    let decimals = decimals.as_u32();
+    tracing::info!(base_token=?base_token, decimals=decimals, "ReadBaseDecimals");
+
+    Ok(decimals)
+}
+
 pub trait CompactSerializable {
     fn compact_ser(&self) -> String;
 }
@@ -136,6 +384,10 @@ impl EventsSerializable for Events {
             shorts: self.shorts.to_hashmap(),
             lps: self.lps.to_hashmap(),
             share_prices: self.share_prices.to_hashmap(),
+            router_longs: self.router_longs.to_hashmap(),
+            router_shorts: self.router_shorts.to_hashmap(),
+            router_lps: self.router_lps.to_hashmap(),
+            unattributed_gas: self.unattributed_gas.to_hashmap(),
         }
     }
 
@@ -145,6 +397,10 @@ impl EventsSerializable for Events {
             shorts: sevents.shorts.clone().into_iter().collect(),
             lps: sevents.lps.clone().into_iter().collect(),
             share_prices: sevents.share_prices.clone().into_iter().collect(),
+            router_longs: sevents.router_longs.clone().into_iter().collect(),
+            router_shorts: sevents.router_shorts.clone().into_iter().collect(),
+            router_lps: sevents.router_lps.clone().into_iter().collect(),
+            unattributed_gas: sevents.unattributed_gas.clone().into_iter().collect(),
         }
     }
 }
@@ -231,9 +487,377 @@ impl<'de> Deserialize<'de> for LpKey {
     }
 }
 
+impl FromStr for GroupBy {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "address" => Ok(GroupBy::Address),
+            "pool_type" => Ok(GroupBy::PoolType),
+            "base_token" => Ok(GroupBy::BaseToken),
+            "all" => Ok(GroupBy::All),
+            _ => Err(eyre!("Invalid grouping level: {}", s)),
+        }
+    }
+}
+
+impl FromStr for Valuation {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "maturity" => Ok(Valuation::Maturity),
+            "market" => Ok(Valuation::Market),
+            _ => Err(eyre!("Invalid valuation mode: {}", s)),
+        }
+    }
+}
+
+impl FromStr for CostBasis {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fifo" => Ok(CostBasis::Fifo),
+            "average_cost" => Ok(CostBasis::AverageCost),
+            _ => Err(eyre!("Invalid cost basis method: {}", s)),
+        }
+    }
+}
+
+impl FromStr for HoldingWeighting {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "exclude" => Ok(HoldingWeighting::Exclude),
+            "pro_rata" => Ok(HoldingWeighting::ProRata),
+            _ => Err(eyre!("Invalid holding weighting: {}", s)),
+        }
+    }
+}
+
+impl PriceBook {
+    ///Latest price at or before timestamp.
+    pub fn price_at(&self, base_token: H160, timestamp: U256) -> Option<Decimal> {
+        self.prices
+            .get(&base_token)?
+            .range(..=timestamp.as_u64())
+            .next_back()
+            .map(|(_, price)| *price)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PriceTimestamp {
+    Unix(u64),
+    Text(String),
+}
+
+#[derive(Deserialize)]
+struct PriceRecord {
+    timestamp: PriceTimestamp,
+    base_token: H160,
+    price: Decimal,
+}
+
+///Timestamps are in seconds, RFC 3339 or `%Y-%m-%d` for midnight UTC.
+fn parse_price_timestamp(timestamp: PriceTimestamp) -> Result<u64> {
+    let text = match timestamp {
+        PriceTimestamp::Unix(seconds) => return Ok(seconds),
+        PriceTimestamp::Text(text) => text,
+    };
+    if let Ok(seconds) = text.parse::<u64>() {
+        return Ok(seconds);
+    }
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(&text) {
+        return Ok(datetime.timestamp().try_into()?);
+    }
+    let datetime = NaiveDate::parse_from_str(&text, "%Y-%m-%d")
+        .map_err(|_| eyre!("Invalid price timestamp: {}", text))?
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc();
+    Ok(datetime.timestamp().try_into()?)
+}
+
+///Reads `timestamp,base_token,price` records from a JSON array, or else from a CSV file.
+pub fn read_prices(path: &Path) -> Result<PriceBook> {
+    let records: Vec<PriceRecord> = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str(&fs::read_to_string(path)?)?
+    } else {
+        csv::Reader::from_path(path)?
+            .deserialize()
+            .collect::<Result<_, _>>()?
+    };
+
+    let mut price_book = PriceBook::default();
+    for record in records {
+        price_book
+            .prices
+            .entry(record.base_token)
+            .or_default()
+            .insert(parse_price_timestamp(record.timestamp)?, record.price);
+    }
+    Ok(price_book)
+}
+
+impl TradeFees {
+    pub fn total(&self) -> Decimal {
+        self.curve + self.flat
+    }
+}
+
+impl GasCost {
+    ///In wei.
+    pub fn fee(&self) -> U256 {
+        self.gas_used * self.effective_gas_price
+    }
+}
+
+impl FromStr for Attribution {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tx_from" => Ok(Attribution::TxFrom),
+            "destination" => Ok(Attribution::Destination),
+            _ => Err(eyre!("Invalid attribution: {}", s)),
+        }
+    }
+}
+
+impl FromStr for AddressPattern {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        match s.strip_suffix('*') {
+            Some(prefix) => {
+                let prefix = prefix.trim_start_matches("0x").to_lowercase();
+                if prefix.len() > 40 || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(eyre!("Invalid address prefix: {}", s));
+                }
+                Ok(AddressPattern::Prefix(prefix))
+            }
+            None => Ok(AddressPattern::Exact(
+                s.parse().map_err(|_| eyre!("Invalid address: {}", s))?,
+            )),
+        }
+    }
+}
+
+impl AddressPattern {
+    pub fn matches(&self, address: &H160) -> bool {
+        match self {
+            AddressPattern::Exact(exact) => exact == address,
+            AddressPattern::Prefix(prefix) => format!("{:x}", address).starts_with(prefix),
+        }
+    }
+}
+
+impl AddressFilter {
+    pub fn accepts(&self, address: &H160) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|p| p.matches(address)))
+            && !self.deny.iter().any(|p| p.matches(address))
+    }
+}
+
+///Patterns from a file, one per line with `#` comments, or else from a comma-separated list.
+pub fn read_address_patterns(list: &str) -> Result<Vec<AddressPattern>> {
+    let content = match fs::read_to_string(list) {
+        Ok(content) => content,
+        Err(_) => list.replace(',', "\n"),
+    };
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ParticipantEntry {
+    Address(H160),
+    User { address: H160 },
+}
+
+///Reads registered addresses from a JSON array of addresses or of `{"address": ..}` users, or
+///else from a CSV with an `address` column, as exported from the `users` table.
+pub fn read_participants(path: &Path) -> Result<BTreeSet<H160>> {
+    if path.extension().is_some_and(|ext| ext == "json") {
+        let entries: Vec<ParticipantEntry> = serde_json::from_str(&fs::read_to_string(path)?)?;
+        return Ok(entries
+            .into_iter()
+            .map(|entry| match entry {
+                ParticipantEntry::Address(address) => address,
+                ParticipantEntry::User { address } => address,
+            })
+            .collect());
+    }
+
+    #[derive(Deserialize)]
+    struct UserRecord {
+        address: H160,
+    }
+    csv::Reader::from_path(path)?
+        .deserialize()
+        .map(|record| Ok(record.map(|user: UserRecord| user.address)?))
+        .collect()
+}
+
+impl Identities {
+    pub fn participant(&self, address: &H160) -> Option<&str> {
+        self.participants.get(address).map(String::as_str)
+    }
+
+    ///Address the aggregates of `address` are merged under, itself if unmapped.
+    pub fn primary_address(&self, address: &H160) -> H160 {
+        self.participant(address)
+            .and_then(|participant| self.primary_addresses.get(participant))
+            .copied()
+            .unwrap_or(*address)
+    }
+}
+
+#[derive(Deserialize)]
+struct IdentityRecord {
+    address: H160,
+    participant: String,
+}
+
+///Reads an `address,participant` CSV file.
+pub fn read_identities(path: &Path) -> Result<Identities> {
+    let mut identities = Identities::default();
+    for record in csv::Reader::from_path(path)?.deserialize() {
+        let IdentityRecord {
+            address,
+            participant,
+        } = record?;
+        match identities.participants.get(&address) {
+            Some(existing) if *existing == participant => continue,
+            Some(existing) => {
+                tracing::warn!(address=?address, participant=%participant, existing=%existing, "AddressInSeveralParticipants");
+                let conflicts = identities
+                    .conflicts
+                    .entry(address)
+                    .or_insert_with(|| vec![existing.clone()]);
+                if !conflicts.contains(&participant) {
+                    conflicts.push(participant);
+                }
+                continue;
+            }
+            None => (),
+        }
+        identities
+            .primary_addresses
+            .entry(participant.clone())
+            .and_modify(|primary| *primary = H160::min(*primary, address))
+            .or_insert(address);
+        identities.participants.insert(address, participant);
+    }
+    Ok(identities)
+}
+
+#[derive(Deserialize)]
+struct FundingRecord {
+    address: H160,
+    funder: H160,
+}
+
+///Reads an `address,funder` CSV file, such as first incoming transfers exported from an explorer.
+pub fn read_funders(path: &Path) -> Result<BTreeMap<H160, H160>> {
+    let mut funders = BTreeMap::new();
+    for record in csv::Reader::from_path(path)?.deserialize() {
+        let FundingRecord { address, funder } = record?;
+        funders.entry(address).or_insert(funder);
+    }
+    Ok(funders)
+}
+
+impl GroupBy {
+    pub fn group_key(&self, tconf: &SingleTrackerConfig) -> PoolGroupKey {
+        match self {
+            GroupBy::Address => PoolGroupKey {
+                pool_type: Some(tconf.hconf.pool_type),
+                pool_address: Some(tconf.hconf.address),
+                base_token: Some(tconf.pool_config.base_token),
+            },
+            GroupBy::PoolType => PoolGroupKey {
+                pool_type: Some(tconf.hconf.pool_type),
+                pool_address: None,
+                base_token: None,
+            },
+            GroupBy::BaseToken => PoolGroupKey {
+                pool_type: None,
+                pool_address: None,
+                base_token: Some(tconf.pool_config.base_token),
+            },
+            GroupBy::All => PoolGroupKey {
+                pool_type: None,
+                pool_address: None,
+                base_token: None,
+            },
+        }
+    }
+}
+
+impl PoolGroupKey {
+    ///Non-empty label unique per group: `pool_type-0xaddress`, `pool_type`, `base-0xtoken` or `all`
+    ///depending on the grouping level.
+    pub fn label(&self) -> String {
+        match (self.pool_type, self.pool_address, self.base_token) {
+            (Some(pool_type), Some(pool_address), _) => format!("{}-{:?}", pool_type, pool_address),
+            (Some(pool_type), None, _) => pool_type.to_string(),
+            (None, _, Some(base_token)) => format!("base-{:?}", base_token),
+            (None, _, None) => "all".to_string(),
+        }
+    }
+}
+
+pub fn eventsdb_filename(hconf: &HyperdriveConfig) -> String {
+    format!("{}-{}.json", hconf.pool_type, hconf.address)
+}
+
+///Fails if the events DB was not acquired past `at_block_num`, acquisition ends being exclusive.
+pub fn ensure_eventsdb_covers(
+    hconf: &HyperdriveConfig,
+    events_db: &EventsDb,
+    at_block_num: U64,
+) -> Result<()> {
+    if events_db.end_block_num <= at_block_num.as_u64() {
+        return Err(eyre!(
+            "events DB of {:?} ends at block {}, acquire up to {} first",
+            hconf.address,
+            events_db.end_block_num,
+            at_block_num
+        ));
+    }
+    Ok(())
+}
+
+///Latest block every existing events DB covers, the default block to report at.
+pub fn eventsdbs_last_block_num() -> Result<U64> {
+    let mut last_block_num: Option<u64> = None;
+    for hconf in HYPERDRIVES.values() {
+        let Ok(json_str) = fs::read_to_string(eventsdb_filename(hconf)) else {
+            continue;
+        };
+        let events_db: EventsDb = serde_json::from_str(&json_str)?;
+        let block_num = events_db.end_block_num.saturating_sub(1);
+        last_block_num = Some(last_block_num.map_or(block_num, |b| b.min(block_num)));
+    }
+    last_block_num
+        .map(U64::from)
+        .ok_or_else(|| eyre!("no events DB found, run acq first"))
+}
+
 // [TODO] Replace all DashMap by HashMap. Would thus make this code more easily reusable.
 pub fn read_eventsdb(hconf: &HyperdriveConfig) -> Result<(Arc<Events>, U64)> {
-    match fs::read_to_string(format!("{}-{}.json", hconf.pool_type, hconf.address)) {
+    match fs::read_to_string(eventsdb_filename(hconf)) {
         Ok(events_data) => {
             let events_db: EventsDb = serde_json::from_str(&events_data)?;
 
@@ -254,9 +878,249 @@ pub fn read_eventsdb(hconf: &HyperdriveConfig) -> Result<(Arc<Events>, U64)> {
                 shorts: DashMap::new(),
                 lps: DashMap::new(),
                 share_prices: DashMap::new(),
+                router_longs: DashMap::new(),
+                router_shorts: DashMap::new(),
+                router_lps: DashMap::new(),
+                unattributed_gas: DashMap::new(),
             });
             let start_block_num = hconf.deploy_block_num;
             Ok((events, start_block_num))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    ///Decimal of `negative` and `abs` at `decimals`, rounded half to even at `DECIMAL_PRECISION`
+    ///digits on the decimal string. None when the mantissa does not fit a `Decimal`.
+    fn expected_decimal(negative: bool, abs: U256, decimals: u32) -> Option<Decimal> {
+        let digits = abs.to_string();
+        let (mantissa, scale) = if decimals <= DECIMAL_PRECISION {
+            (abs, decimals)
+        } else {
+            let dropped_len = (decimals - DECIMAL_PRECISION) as usize;
+            let padded = format!("{:0>width$}", digits, width = dropped_len + 1);
+            let (kept, dropped) = padded.split_at(padded.len() - dropped_len);
+            let kept = U256::from_dec_str(kept).unwrap();
+            let half = format!("5{}", "0".repeat(dropped_len - 1));
+            let round_up = match dropped.cmp(half.as_str()) {
+                std::cmp::Ordering::Greater => true,
+                std::cmp::Ordering::Equal => kept.bit(0),
+                std::cmp::Ordering::Less => false,
+            };
+            let kept = if round_up { kept + U256::one() } else { kept };
+            (kept, DECIMAL_PRECISION)
+        };
+        if mantissa.bits() > 96 {
+            return None;
+        }
+        let val_dec = Decimal::from_i128_with_scale(mantissa.as_u128() as i128, scale);
+        Some(if negative { -val_dec } else { val_dec })
+    }
+
+    #[test]
+    fn try_scaled_u256_max() {
+        for decimals in 0..=U256_MAX_EXP10 + DECIMAL_PRECISION + 1 {
+            let scaled = U256::MAX.try_scaled(decimals);
+            assert_eq!(
+                scaled.ok(),
+                expected_decimal(false, U256::MAX, decimals),
+                "{}",
+                decimals
+            );
+        }
+        // U256::MAX is about 1.16e77, whose mantissa fits 96 bits from 57 decimals on.
+        assert!(U256::MAX.try_scaled(56).is_err());
+        assert_eq!(
+            U256::MAX.try_scaled(57).unwrap(),
+            Decimal::from_str("115792089237316195423.57098501").unwrap()
+        );
+        assert_eq!(
+            U256::MAX
+                .try_scaled(U256_MAX_EXP10 + DECIMAL_PRECISION + 1)
+                .unwrap(),
+            Decimal::ZERO
+        );
+    }
+
+    #[test]
+    fn try_scaled_i256_bounds() {
+        for decimals in 0..=U256_MAX_EXP10 + DECIMAL_PRECISION + 1 {
+            let (_, max_abs) = I256::MAX.into_sign_and_abs();
+            assert_eq!(
+                I256::MAX.try_scaled(decimals).ok(),
+                expected_decimal(false, max_abs, decimals),
+                "{}",
+                decimals
+            );
+            let (_, min_abs) = I256::MIN.into_sign_and_abs();
+            assert_eq!(
+                I256::MIN.try_scaled(decimals).ok(),
+                expected_decimal(true, min_abs, decimals),
+                "{}",
+                decimals
+            );
+        }
+        assert!(I256::MAX.try_scaled(DECIMAL_SCALE).is_err());
+        assert!(I256::MIN.try_scaled(DECIMAL_SCALE).is_err());
+        assert!(I256::MIN
+            .try_scaled(U256_MAX_EXP10)
+            .unwrap()
+            .is_sign_negative());
+    }
+
+    #[test]
+    fn try_scaled_rounding() {
+        let cases = [
+            ("4999999999", "0"),
+            ("5000000000", "0"),
+            ("5000000001", "0.00000001"),
+            ("15000000000", "0.00000002"),
+            ("25000000000", "0.00000002"),
+            ("25000000001", "0.00000003"),
+            ("1000000000000000000", "1"),
+        ];
+        for (wad, expected) in cases {
+            let expected = Decimal::from_str(expected).unwrap();
+            let wad = I256::from_dec_str(wad).unwrap();
+            assert_eq!(wad.try_scaled(DECIMAL_SCALE).unwrap(), expected, "{}", wad);
+            assert_eq!(
+                (-wad).try_scaled(DECIMAL_SCALE).unwrap(),
+                -expected,
+                "{}",
+                wad
+            );
+        }
+
+        // Largest mantissa, and rounding up past it.
+        let max_mantissa = (U256::one() << 96) - U256::one();
+        assert_eq!(
+            max_mantissa.try_scaled(DECIMAL_PRECISION).unwrap(),
+            Decimal::MAX / Decimal::from(100_000_000)
+        );
+        assert!((max_mantissa + U256::one())
+            .try_scaled(DECIMAL_PRECISION)
+            .is_err());
+        let tenfold = max_mantissa * U256::from(10);
+        assert!((tenfold + U256::from(4))
+            .try_scaled(DECIMAL_PRECISION + 1)
+            .is_ok());
+        assert!((tenfold + U256::from(5))
+            .try_scaled(DECIMAL_PRECISION + 1)
+            .is_err());
+    }
+
+    ///Whole range of U256, shifted down at random so that every magnitude is covered.
+    fn any_u256() -> impl Strategy<Value = U256> {
+        (any::<[u64; 4]>(), 0..256usize).prop_map(|(words, shift)| U256(words) >> shift)
+    }
+
+    ///`value` back from its decimal at `decimals`, and the most rounding may have taken off.
+    fn unscaled(value: Decimal, decimals: u32) -> (U256, U256) {
+        let mantissa = U256::from(value.mantissa().unsigned_abs());
+        let back = mantissa * U256::exp10((decimals - value.scale()) as usize);
+        let tolerance = match decimals.checked_sub(DECIMAL_PRECISION) {
+            Some(dropped) if dropped > 0 => U256::exp10(dropped as usize) / 2,
+            _ => U256::zero(),
+        };
+        (back, tolerance)
+    }
+
+    proptest! {
+        #[test]
+        fn try_scaled_u256_props(value in any_u256(), decimals in 0..=36u32) {
+            let scaled = value.try_scaled(decimals);
+            prop_assert_eq!(
+                scaled.as_ref().ok().copied(),
+                expected_decimal(false, value, decimals)
+            );
+            if let Ok(scaled) = scaled {
+                prop_assert!(scaled.scale() <= DECIMAL_PRECISION);
+                let (back, tolerance) = unscaled(scaled, decimals);
+                let error = if back > value { back - value } else { value - back };
+                prop_assert!(error <= tolerance, "{} {} {}", value, back, tolerance);
+            }
+        }
+
+        #[test]
+        fn try_scaled_i256_props(
+            raw in any_u256(),
+            negative in any::<bool>(),
+            decimals in 0..=36u32,
+        ) {
+            let value = I256::from_raw(raw);
+            let value = if negative && value != I256::MIN { -value } else { value };
+            let (sign, abs) = value.into_sign_and_abs();
+            let scaled = value.try_scaled(decimals);
+            prop_assert_eq!(
+                scaled.as_ref().ok().copied(),
+                expected_decimal(sign.is_negative(), abs, decimals)
+            );
+            if let Ok(scaled) = scaled {
+                prop_assert!(scaled.is_zero() || scaled.is_sign_negative() == sign.is_negative());
+                let (back, tolerance) = unscaled(scaled, decimals);
+                let error = if back > abs { back - abs } else { abs - back };
+                prop_assert!(error <= tolerance, "{} {} {}", value, back, tolerance);
+            }
+        }
+
+        #[test]
+        fn scaled_never_panics(value in any_u256(), decimals in 0..=36u32) {
+            let scaled = value.scaled(decimals);
+            prop_assert_eq!(scaled, value.try_scaled(decimals).unwrap_or(Decimal::ZERO));
+            let _ = I256::from_raw(value).scaled(decimals);
+            let _ = value.to_wad(decimals);
+            let _ = I256::from_raw(value).to_wad(decimals);
+        }
+    }
+
+    #[test]
+    fn to_wad_overflow_counts_as_zero() {
+        let overflows = DECIMAL_OVERFLOWS.load(Ordering::Relaxed);
+        assert_eq!(U256::MAX.to_wad(6), U256::zero());
+        assert_eq!(I256::MIN.to_wad(6), I256::zero());
+        assert!(DECIMAL_OVERFLOWS.load(Ordering::Relaxed) >= overflows + 2);
+        assert_eq!(U256::from(1_000_000).to_wad(6), U256::exp10(18));
+        assert_eq!(I256::from(-1_000_000).to_wad(6), -I256::exp10(18));
+        assert_eq!(U256::exp10(24).to_wad(24), U256::exp10(18));
+    }
+
+    #[test]
+    fn fixed_rate_of_dust_bonds() {
+        let position_duration = U256::from(SECONDS_PER_YEAR);
+        let dust = I256::from(4_999_999_999u64);
+        assert_eq!(calc_long_fixed_rate(dust, dust, position_duration), None);
+        assert_eq!(calc_short_fixed_rate(dust, dust, position_duration), None);
+        assert_eq!(
+            calc_long_fixed_rate(I256::zero(), dust, position_duration),
+            None
+        );
+
+        let bonds = I256::exp10(18);
+        let base = I256::from(950_000_000_000_000_000u64);
+        assert_eq!(
+            calc_long_fixed_rate(base, bonds, position_duration),
+            Some(Decimal::from_str("0.05263158").unwrap())
+        );
+        assert_eq!(
+            calc_short_fixed_rate(I256::exp10(18) - base, bonds, position_duration),
+            Some(Decimal::from_str("0.05263158").unwrap())
+        );
+    }
+
+    #[test]
+    fn scaled_overflow_counts_as_zero() {
+        let overflows = DECIMAL_OVERFLOWS.load(Ordering::Relaxed);
+        assert_eq!(U256::MAX.normalized(), Decimal::ZERO);
+        assert_eq!(I256::MIN.scaled(DECIMAL_SCALE), Decimal::ZERO);
+        assert!(DECIMAL_OVERFLOWS.load(Ordering::Relaxed) >= overflows + 2);
+        assert_eq!(
+            U256::exp10(18).normalized(),
+            U256::exp10(18).scaled(DECIMAL_SCALE)
+        );
+    }
+}
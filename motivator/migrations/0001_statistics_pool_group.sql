@@ -0,0 +1,6 @@
+-- Statistics rows are keyed by pool group rather than pool type, which is not unique when
+-- aggregating per pool address. Run before `yarn db-push` on databases predating the column.
+ALTER TABLE public.statistics ADD COLUMN IF NOT EXISTS pool_group text;
+UPDATE public.statistics SET pool_group = pool_type WHERE pool_group IS NULL;
+DROP INDEX IF EXISTS idx_statistics_on_timestamp_pool_user;
+CREATE UNIQUE INDEX IF NOT EXISTS idx_statistics_on_timestamp_group_user ON public.statistics (timestamp, pool_group, user_address);
@@ -10,6 +10,7 @@ import {
     pgTable,
     text,
     timestamp,
+    uniqueIndex,
     uuid,
 } from 'drizzle-orm/pg-core'
 
@@ -48,28 +49,38 @@ export const assessor_slot_user = pgTable('assessor_slot_user', {
 /**
  * Specific table needs to be extracted
  */
-export const statistics = pgTable('statistics', {
-    id: uuid('id').defaultRandom().unique().primaryKey(),
-    timestamp: timestamp('timestamp', {
-        precision: 3,
-        mode: 'string',
-        withTimezone: false,
-    }),
-    poolType: text('pool_type'),
-    user_address: text('user_address').references(() => user.address),
-    action_count_longs: doublePrecision('action_count_longs'),
-    action_count_shorts: doublePrecision('action_count_shorts'),
-    action_count_lps: doublePrecision('action_count_lps'),
-    volume_longs: doublePrecision('volume_longs'),
-    volume_shorts: doublePrecision('volume_shorts'),
-    volume_lps: doublePrecision('volume_lps'),
-    pnl_longs: doublePrecision('pnl_longs'),
-    pnl_shorts: doublePrecision('pnl_shorts'),
-    pnl_lps: doublePrecision('pnl_lps'),
-    tvl_longs: doublePrecision('tvl_longs'),
-    tvl_shorts: doublePrecision('tvl_shorts'),
-    tvl_lps: doublePrecision('tvl_lps'),
-})
+export const statistics = pgTable(
+    'statistics',
+    {
+        id: uuid('id').defaultRandom().unique().primaryKey(),
+        timestamp: timestamp('timestamp', {
+            precision: 3,
+            mode: 'string',
+            withTimezone: false,
+        }),
+        // Unique per pool group, which poolType alone is not when grouping by pool address
+        poolGroup: text('pool_group'),
+        poolType: text('pool_type'),
+        user_address: text('user_address').references(() => user.address),
+        action_count_longs: doublePrecision('action_count_longs'),
+        action_count_shorts: doublePrecision('action_count_shorts'),
+        action_count_lps: doublePrecision('action_count_lps'),
+        volume_longs: doublePrecision('volume_longs'),
+        volume_shorts: doublePrecision('volume_shorts'),
+        volume_lps: doublePrecision('volume_lps'),
+        pnl_longs: doublePrecision('pnl_longs'),
+        pnl_shorts: doublePrecision('pnl_shorts'),
+        pnl_lps: doublePrecision('pnl_lps'),
+        tvl_longs: doublePrecision('tvl_longs'),
+        tvl_shorts: doublePrecision('tvl_shorts'),
+        tvl_lps: doublePrecision('tvl_lps'),
+    },
+    (table) => ({
+        timestampGroupUserIdx: uniqueIndex(
+            'idx_statistics_on_timestamp_group_user'
+        ).on(table.timestamp, table.poolGroup, table.user_address),
+    })
+)
 /**
  * Specific table needs to be extracted
  */